@@ -0,0 +1,307 @@
+use crate::Service;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// ring points per unit of weight - more points spread the keys more evenly
+const POINTS_PER_WEIGHT: u32 = 16;
+const MAX_POINTS_PER_SERVICE: u32 = 1024;
+
+pub fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// consistent-hash ring over the registered services
+//
+// every service owns points proportional to its weight, a key belongs to the first
+// point at or after its hash - so a service joining or leaving only moves the keys
+// next to its own points
+#[derive(Debug, Default)]
+pub struct HashRing {
+    points: Vec<(u64, String)>,
+}
+
+impl HashRing {
+    pub fn build(services: &[Service]) -> Self {
+        // zero-weight services only take keys when every service has zero weight
+        let all_zero = services.iter().all(|s| s.weight == 0);
+        let mut points = Vec::new();
+        for service in services {
+            let weight = if all_zero { 1 } else { service.weight };
+            let count = weight
+                .saturating_mul(POINTS_PER_WEIGHT)
+                .min(MAX_POINTS_PER_SERVICE);
+            for i in 0..count {
+                let point = hash_key(&format!("{}#{}", service.name, i));
+                points.push((point, service.name.clone()));
+            }
+        }
+        points.sort();
+        Self { points }
+    }
+
+    // the service owning `key`, walking on past services `accept` turns down
+    // (unhealthy, wrong model, already failed this request)
+    pub fn lookup(&self, key: &str, accept: impl Fn(&str) -> bool) -> Option<&str> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let hash = hash_key(key);
+        let start = self.points.partition_point(|(point, _)| *point < hash);
+        (0..self.points.len())
+            .map(|i| self.points[(start + i) % self.points.len()].1.as_str())
+            .find(|name| accept(name))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrefixSettings {
+    // bytes of the conversation that make up the routing prefix, 0 disables prefix routing
+    pub prefix_bytes: usize,
+    // how far above its weighted share of the in-flight requests a backend may go
+    // before a prefix is routed elsewhere
+    pub load_factor: f64,
+    // llama-api-server evicts old slots, an older hit is unlikely to still be cached
+    pub ttl: Duration,
+    pub max_entries: usize,
+}
+
+impl Default for PrefixSettings {
+    fn default() -> Self {
+        Self {
+            prefix_bytes: 0,
+            load_factor: 1.25,
+            ttl: Duration::from_secs(300),
+            max_entries: 10_000,
+        }
+    }
+}
+
+// hash of the model, the leading system message(s) and at most `prefix_bytes` of the
+// turns after them
+//
+// the last message is the new turn and never part of the key, so every user sending the
+// same system prompt gets the same key and lands where that prompt is already in the kv
+// cache - a conversation going on gets the same key as its earlier turns as long as they
+// fill `prefix_bytes`
+pub fn prefix_key(payload: &serde_json::Value, prefix_bytes: usize) -> Option<u64> {
+    let messages = payload.get("messages")?.as_array()?;
+    let (_, earlier) = messages.split_last()?;
+    // nothing but the new turn, no prefix any backend could have cached
+    if earlier.is_empty() {
+        return None;
+    }
+    let system_count = earlier
+        .iter()
+        .take_while(|m| m.get("role").and_then(|r| r.as_str()) == Some("system"))
+        .count();
+
+    let mut prefix = String::new();
+    if let Some(model) = payload.get("model").and_then(|m| m.as_str()) {
+        prefix.push_str(model);
+        prefix.push('\n');
+    }
+    for message in &earlier[..system_count] {
+        push_message(&mut prefix, message);
+    }
+
+    let mut history = String::new();
+    for message in &earlier[system_count..] {
+        push_message(&mut history, message);
+        if history.len() >= prefix_bytes {
+            break;
+        }
+    }
+    let mut end = prefix_bytes.min(history.len());
+    while !history.is_char_boundary(end) {
+        end -= 1;
+    }
+    prefix.push_str(&history[..end]);
+    Some(hash_key(&prefix))
+}
+
+fn push_message(prefix: &mut String, message: &serde_json::Value) {
+    let role = message.get("role").and_then(|r| r.as_str()).unwrap_or("");
+    prefix.push_str(role);
+    prefix.push('\n');
+    match message.get("content") {
+        Some(serde_json::Value::String(text)) => prefix.push_str(text.trim()),
+        Some(other) => prefix.push_str(&other.to_string()),
+        None => {}
+    }
+    prefix.push('\n');
+}
+
+// whether a backend with `in_flight` requests can take one more without going past
+// `load_factor` times its `share` (by weight) of all in-flight requests
+pub fn within_bounded_load(
+    in_flight: usize,
+    share: f64,
+    total_in_flight: usize,
+    load_factor: f64,
+) -> bool {
+    let capacity = ((total_in_flight + 1) as f64 * share * load_factor).ceil();
+    (in_flight as f64) < capacity
+}
+
+// which service most recently served each prompt prefix
+#[derive(Default)]
+pub struct PrefixTable {
+    entries: Mutex<HashMap<u64, (String, Instant)>>,
+}
+
+impl PrefixTable {
+    pub fn lookup(&self, key: u64, settings: &PrefixSettings) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        let (service, at) = entries.get(&key)?;
+        (at.elapsed() < settings.ttl).then(|| service.clone())
+    }
+
+    pub fn remember(&self, key: u64, service: &str, settings: &PrefixSettings) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= settings.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, (_, at)| at.elapsed() < settings.ttl);
+            // still full - make room by dropping the least recently used prefix
+            if entries.len() >= settings.max_entries
+                && let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, (_, at))| *at)
+                    .map(|(k, _)| *k)
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (service.to_string(), Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, weight: u32) -> Service {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "weight": weight,
+            "ip": "127.0.0.1",
+            "port": 8080,
+        }))
+        .unwrap()
+    }
+
+    fn owners(ring: &HashRing) -> Vec<String> {
+        (0..1000)
+            .map(|i| {
+                ring.lookup(&format!("session-{}", i), |_| true)
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_ring_has_no_owner() {
+        assert_eq!(HashRing::build(&[]).lookup("key", |_| true), None);
+    }
+
+    #[test]
+    fn lookup_walks_past_rejected_services() {
+        let ring = HashRing::build(&[service("a", 1), service("b", 1)]);
+        let owner = ring.lookup("key", |_| true).unwrap();
+        assert_eq!(ring.lookup("key", |_| true), Some(owner));
+        let other = if owner == "a" { "b" } else { "a" };
+        assert_eq!(ring.lookup("key", |name| name != owner), Some(other));
+        assert_eq!(ring.lookup("key", |_| false), None);
+    }
+
+    #[test]
+    fn removing_a_service_only_moves_its_keys() {
+        let before = owners(&HashRing::build(&[
+            service("a", 1),
+            service("b", 1),
+            service("c", 1),
+        ]));
+        let after = owners(&HashRing::build(&[service("a", 1), service("b", 1)]));
+        for (before, after) in before.iter().zip(&after) {
+            if before != "c" {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_follow_weights() {
+        let weighted = owners(&HashRing::build(&[
+            service("heavy", 4),
+            service("light", 1),
+        ]));
+        let heavy = weighted.iter().filter(|o| *o == "heavy").count();
+        assert!(heavy > 650, "heavy service got {} of 1000 keys", heavy);
+
+        // a zero-weight service gets no keys while others have weight
+        let idle = owners(&HashRing::build(&[service("a", 1), service("idle", 0)]));
+        assert!(idle.iter().all(|o| o == "a"));
+    }
+
+    #[test]
+    fn all_zero_weights_still_share_keys() {
+        let owners = owners(&HashRing::build(&[service("a", 0), service("b", 0)]));
+        assert!(owners.iter().any(|o| o == "a"));
+        assert!(owners.iter().any(|o| o == "b"));
+    }
+
+    fn chat(messages: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "model": "llama-3.2-1b", "messages": messages })
+    }
+
+    #[test]
+    fn users_sharing_a_system_prompt_share_a_key() {
+        let first = chat(serde_json::json!([
+            { "role": "system", "content": "You are a helpful assistant." },
+            { "role": "user", "content": "hi" },
+        ]));
+        let second = chat(serde_json::json!([
+            { "role": "system", "content": "You are a helpful assistant." },
+            { "role": "user", "content": "what is the weather like?" },
+        ]));
+        let other_prompt = chat(serde_json::json!([
+            { "role": "system", "content": "You translate to French." },
+            { "role": "user", "content": "hi" },
+        ]));
+        let key = prefix_key(&first, 256).unwrap();
+        assert_eq!(prefix_key(&second, 256), Some(key));
+        assert_ne!(prefix_key(&other_prompt, 256), Some(key));
+    }
+
+    #[test]
+    fn history_is_cut_to_prefix_bytes() {
+        let short = chat(serde_json::json!([
+            { "role": "system", "content": "prompt" },
+            { "role": "user", "content": "0123456789" },
+            { "role": "assistant", "content": "a" },
+            { "role": "user", "content": "next" },
+        ]));
+        let longer = chat(serde_json::json!([
+            { "role": "system", "content": "prompt" },
+            { "role": "user", "content": "0123456789 and more" },
+            { "role": "assistant", "content": "b" },
+            { "role": "user", "content": "other" },
+        ]));
+        assert_eq!(prefix_key(&short, 8), prefix_key(&longer, 8));
+        assert_ne!(prefix_key(&short, 64), prefix_key(&longer, 64));
+    }
+
+    #[test]
+    fn no_key_without_a_prefix() {
+        let single = chat(serde_json::json!([{ "role": "user", "content": "hi" }]));
+        assert_eq!(prefix_key(&single, 256), None);
+        assert_eq!(prefix_key(&chat(serde_json::json!([])), 256), None);
+        assert_eq!(
+            prefix_key(&serde_json::json!({ "prompt": "hi" }), 256),
+            None
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+// api keys accepted on the `/v1/*` routes, mapped to the name requests are accounted under
+#[derive(Default)]
+pub struct KeyStore {
+    keys: RwLock<HashMap<String, String>>,
+}
+
+// a key as listed by `GET /api/keys` - the key itself is never shown again
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyInfo {
+    pub name: String,
+    pub key_hint: String,
+}
+
+// `sk-abcd...wxyz`, enough to tell keys apart in logs and listings
+pub fn mask_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 12 {
+        return "***".to_string();
+    }
+    let head: String = chars[..7].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", head, tail)
+}
+
+pub fn generate_key() -> String {
+    let random: String = rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    format!("sk-{}", random)
+}
+
+// the token of an `Authorization: Bearer <token>` header value
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+// compares secrets without bailing out at the first differing byte
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+// an address range such as `10.42.0.0/16`, a bare address matches just itself
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, len)) => (address, Some(len)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid address '{}'", address))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u32>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length '{}'", len))?,
+            None => max_len,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 clients may show up as `::ffff:a.b.c.d` on a dual-stack listener
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl KeyStore {
+    // reads keys from a file with one `name:key` (or bare `key`) per line,
+    // blank lines and lines starting with `#` are skipped
+    pub fn load_file(&self, path: &str) -> std::io::Result<usize> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut loaded = 0;
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, key) = match line.split_once(':') {
+                Some((name, key)) => (name.trim().to_string(), key.trim()),
+                None => (mask_key(line), line),
+            };
+            self.insert(name, key.to_string());
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    pub fn insert(&self, name: String, key: String) {
+        self.keys.write().unwrap().insert(key, name);
+    }
+
+    // removes every key registered under `name`, returns how many there were
+    pub fn revoke(&self, name: &str) -> usize {
+        let mut keys = self.keys.write().unwrap();
+        let before = keys.len();
+        keys.retain(|_, n| n != name);
+        before - keys.len()
+    }
+
+    // name of the key, `None` if it is not a known key
+    pub fn authenticate(&self, key: &str) -> Option<String> {
+        self.keys.read().unwrap().get(key).cloned()
+    }
+
+    pub fn list(&self) -> Vec<KeyInfo> {
+        let mut keys: Vec<KeyInfo> = self
+            .keys
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, name)| KeyInfo {
+                name: name.clone(),
+                key_hint: mask_key(key),
+            })
+            .collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        let range: Cidr = "10.42.0.0/16".parse().unwrap();
+        assert!(range.contains(ip("10.42.7.1")));
+        assert!(!range.contains(ip("10.43.0.1")));
+        // as seen on a dual-stack listener
+        assert!(range.contains(ip("::ffff:10.42.0.9")));
+        assert!(!range.contains(ip("fd00::1")));
+
+        let single: Cidr = " 127.0.0.1 ".parse().unwrap();
+        assert!(single.contains(ip("127.0.0.1")));
+        assert!(!single.contains(ip("127.0.0.2")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("203.0.113.5")));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        let range: Cidr = "fd00:1::/32".parse().unwrap();
+        assert!(range.contains(ip("fd00:1:ffff::1")));
+        assert!(!range.contains(ip("fd00:2::1")));
+        assert!(!range.contains(ip("10.0.0.1")));
+
+        let any: Cidr = "::/0".parse().unwrap();
+        assert!(any.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        for range in [
+            "",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "host",
+        ] {
+            assert!(range.parse::<Cidr>().is_err(), "{} parsed", range);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::time::unix_now;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// transitions kept per service for `GET /api/services`
+const HISTORY_LEN: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct BreakerSettings {
+    // consecutive failed requests that open the circuit, zero disables this trigger
+    pub consecutive_failures: u32,
+    // share of failed requests in the window that opens the circuit, zero disables this trigger
+    pub error_rate_percent: u32,
+    // most recent requests the error rate is computed over
+    pub window: usize,
+    // the error rate is only trusted once the window holds this many requests
+    pub min_requests: usize,
+    // how long an open circuit rejects requests before letting trial requests through
+    pub open_duration: Duration,
+    // trial requests let through while half-open, that many successes close the circuit
+    pub half_open_requests: u32,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            error_rate_percent: 50,
+            window: 20,
+            min_requests: 10,
+            open_duration: Duration::from_secs(30),
+            half_open_requests: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub from: CircuitState,
+    pub to: CircuitState,
+    pub reason: String,
+    // unix timestamp (seconds) of the transition
+    pub at: u64,
+}
+
+#[derive(Debug)]
+pub struct Breaker {
+    state: CircuitState,
+    opened_until: Option<Instant>,
+    consecutive_failures: u32,
+    // outcomes of the most recent requests, `true` for a failure
+    window: VecDeque<bool>,
+    // trial requests admitted and successful since going half-open
+    trials: u32,
+    trial_successes: u32,
+    history: VecDeque<Transition>,
+}
+
+// circuit state as listed by `GET /api/services`
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerStatus {
+    pub state: CircuitState,
+    pub open_remaining_secs: u64,
+    pub consecutive_failures: u32,
+    pub error_rate_percent: u32,
+    pub transitions: Vec<Transition>,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            opened_until: None,
+            consecutive_failures: 0,
+            window: VecDeque::new(),
+            trials: 0,
+            trial_successes: 0,
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl Breaker {
+    fn transition(&mut self, to: CircuitState, reason: String) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(Transition {
+            from: self.state,
+            to,
+            reason,
+            at: unix_now(),
+        });
+        self.state = to;
+        self.trials = 0;
+        self.trial_successes = 0;
+        self.consecutive_failures = 0;
+        self.window.clear();
+    }
+
+    fn open(&mut self, reason: String, settings: &BreakerSettings) {
+        self.opened_until = Some(Instant::now() + settings.open_duration);
+        self.transition(CircuitState::Open, reason);
+    }
+
+    fn error_rate_percent(&self) -> u32 {
+        if self.window.is_empty() {
+            return 0;
+        }
+        let failures = self.window.iter().filter(|failed| **failed).count();
+        (failures * 100 / self.window.len()) as u32
+    }
+
+    // whether the service may be offered to the balancing strategy right now
+    pub fn allows_requests(&self, settings: &BreakerSettings) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => self
+                .opened_until
+                .is_none_or(|until| Instant::now() >= until),
+            CircuitState::HalfOpen => self.trials < settings.half_open_requests.max(1),
+        }
+    }
+
+    // claims the right to send a request, taking one of the trial slots while half-open
+    pub fn admit(&mut self, settings: &BreakerSettings) -> bool {
+        if self.state == CircuitState::Open && self.allows_requests(settings) {
+            self.transition(
+                CircuitState::HalfOpen,
+                format!("open for {}s", settings.open_duration.as_secs()),
+            );
+        }
+        if !self.allows_requests(settings) {
+            return false;
+        }
+        if self.state == CircuitState::HalfOpen {
+            self.trials += 1;
+        }
+        true
+    }
+
+    // records a finished request, returns the new state if it changed
+    pub fn record(
+        &mut self,
+        failure: Option<&str>,
+        settings: &BreakerSettings,
+    ) -> Option<CircuitState> {
+        match (self.state, failure) {
+            // requests admitted before the circuit opened don't count anymore
+            (CircuitState::Open, _) => None,
+            (CircuitState::HalfOpen, Some(error)) => {
+                self.open(format!("trial request failed: {}", error), settings);
+                Some(CircuitState::Open)
+            }
+            (CircuitState::HalfOpen, None) => {
+                self.trial_successes += 1;
+                if self.trial_successes < settings.half_open_requests.max(1) {
+                    return None;
+                }
+                let reason = format!("{} trial requests succeeded", self.trial_successes);
+                self.transition(CircuitState::Closed, reason);
+                Some(CircuitState::Closed)
+            }
+            (CircuitState::Closed, failure) => {
+                if self.window.len() >= settings.window.max(1) {
+                    self.window.pop_front();
+                }
+                self.window.push_back(failure.is_some());
+
+                let Some(error) = failure else {
+                    self.consecutive_failures = 0;
+                    return None;
+                };
+                self.consecutive_failures += 1;
+
+                if settings.consecutive_failures > 0
+                    && self.consecutive_failures >= settings.consecutive_failures
+                {
+                    let reason = format!(
+                        "{} consecutive failures, last: {}",
+                        self.consecutive_failures, error
+                    );
+                    self.open(reason, settings);
+                    return Some(CircuitState::Open);
+                }
+
+                let rate = self.error_rate_percent();
+                if settings.error_rate_percent > 0
+                    && self.window.len() >= settings.min_requests
+                    && rate >= settings.error_rate_percent
+                {
+                    let reason = format!(
+                        "error rate {}% over the last {} requests, last: {}",
+                        rate,
+                        self.window.len(),
+                        error
+                    );
+                    self.open(reason, settings);
+                    return Some(CircuitState::Open);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let remaining = match self.state {
+            CircuitState::Open => self
+                .opened_until
+                .map(|until| until.saturating_duration_since(Instant::now()))
+                .unwrap_or_default(),
+            _ => Duration::ZERO,
+        };
+        BreakerStatus {
+            state: self.state,
+            open_remaining_secs: remaining.as_secs(),
+            consecutive_failures: self.consecutive_failures,
+            error_rate_percent: self.error_rate_percent(),
+            transitions: self.history.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> BreakerSettings {
+        BreakerSettings {
+            consecutive_failures: 3,
+            error_rate_percent: 50,
+            window: 10,
+            min_requests: 6,
+            open_duration: Duration::from_secs(60),
+            half_open_requests: 2,
+        }
+    }
+
+    #[test]
+    fn consecutive_failures_open_the_circuit() {
+        let settings = settings();
+        let mut breaker = Breaker::default();
+        assert_eq!(breaker.record(Some("refused"), &settings), None);
+        assert_eq!(breaker.record(None, &settings), None);
+        assert_eq!(breaker.record(Some("refused"), &settings), None);
+        assert_eq!(breaker.record(Some("refused"), &settings), None);
+        assert_eq!(
+            breaker.record(Some("refused"), &settings),
+            Some(CircuitState::Open)
+        );
+        assert!(!breaker.allows_requests(&settings));
+        assert!(!breaker.admit(&settings));
+        assert_eq!(breaker.status().open_remaining_secs, 59);
+    }
+
+    #[test]
+    fn error_rate_opens_the_circuit_once_the_window_is_full_enough() {
+        let settings = settings();
+        let mut breaker = Breaker::default();
+        for _ in 0..2 {
+            assert_eq!(breaker.record(Some("502"), &settings), None);
+            assert_eq!(breaker.record(None, &settings), None);
+        }
+        // 60% failed but only five requests in the window, fewer than `min_requests`
+        assert_eq!(breaker.record(Some("502"), &settings), None);
+        assert_eq!(breaker.record(None, &settings), None);
+        assert_eq!(
+            breaker.record(Some("502"), &settings),
+            Some(CircuitState::Open)
+        );
+        assert_eq!(breaker.status().transitions.len(), 1);
+    }
+
+    #[test]
+    fn half_open_closes_after_enough_trials() {
+        let settings = BreakerSettings {
+            open_duration: Duration::ZERO,
+            ..settings()
+        };
+        let mut breaker = Breaker::default();
+        for _ in 0..3 {
+            breaker.record(Some("refused"), &settings);
+        }
+        assert_eq!(breaker.status().state, CircuitState::Open);
+
+        assert!(breaker.admit(&settings));
+        assert_eq!(breaker.status().state, CircuitState::HalfOpen);
+        assert!(breaker.admit(&settings));
+        assert!(!breaker.admit(&settings), "only two trial slots");
+        assert_eq!(breaker.record(None, &settings), None);
+        assert_eq!(breaker.record(None, &settings), Some(CircuitState::Closed));
+        assert!(breaker.admit(&settings));
+    }
+
+    #[test]
+    fn failed_trial_reopens_the_circuit() {
+        let settings = BreakerSettings {
+            open_duration: Duration::ZERO,
+            ..settings()
+        };
+        let mut breaker = Breaker::default();
+        for _ in 0..3 {
+            breaker.record(Some("refused"), &settings);
+        }
+        assert!(breaker.admit(&settings));
+        assert_eq!(
+            breaker.record(Some("refused"), &settings),
+            Some(CircuitState::Open)
+        );
+        let transitions = breaker.status().transitions;
+        let states: Vec<_> = transitions.iter().map(|t| t.to).collect();
+        assert_eq!(
+            states,
+            [
+                CircuitState::Open,
+                CircuitState::HalfOpen,
+                CircuitState::Open
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_triggers_never_open() {
+        let settings = BreakerSettings {
+            consecutive_failures: 0,
+            error_rate_percent: 0,
+            ..settings()
+        };
+        let mut breaker = Breaker::default();
+        for _ in 0..50 {
+            assert_eq!(breaker.record(Some("refused"), &settings), None);
+        }
+        assert!(breaker.admit(&settings));
+    }
+}
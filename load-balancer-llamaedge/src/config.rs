@@ -0,0 +1,338 @@
+use crate::affinity::PrefixSettings;
+use crate::auth::Cidr;
+use crate::breaker::BreakerSettings;
+use crate::health::HealthSettings;
+use crate::http::RequestLimits;
+use crate::latency::LatencySettings;
+use crate::lifecycle::LifecycleSettings;
+use crate::log::{self, Level};
+use crate::models::ModelsSettings;
+use crate::outlier::OutlierSettings;
+use crate::pool::PoolSettings;
+use crate::queue::QueueSettings;
+use crate::ratelimit::TenantLimits;
+use crate::timeouts::TimeoutSettings;
+use crate::usage::UsageSettings;
+use std::time::Duration;
+use std::{env, str::FromStr};
+
+// runtime configuration, read once at startup from `LB_*` environment variables
+//
+// most invalid values fall back to their default with a warning, the admin credentials
+// are the exception - a token or allowlist that is configured but can't be loaded is an
+// error, rather than leaving the admin routes open
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub limits: RequestLimits,
+    pub pool: PoolSettings,
+    // how long a kept-alive client connection may sit idle between requests
+    pub client_idle_timeout: Duration,
+    pub retry: RetryPolicy,
+    pub health: HealthSettings,
+    pub outlier: OutlierSettings,
+    pub breaker: BreakerSettings,
+    pub queue: QueueSettings,
+    // name of the balancing strategy to start with, see `strategy::STRATEGY_NAMES`
+    pub strategy: String,
+    pub latency: LatencySettings,
+    // pin requests with the same `X-Session-Id` header or `user` field to one service
+    pub session_affinity: bool,
+    pub prefix: PrefixSettings,
+    // file with `name:key` lines loaded at startup, more keys can be added through `/api/keys`
+    pub api_keys_file: Option<String>,
+    // reject `/v1/*` requests without a known `Authorization: Bearer` key
+    pub require_api_key: bool,
+    // bearer token required on the `/api/*` admin routes, unset leaves them open
+    pub admin_token: Option<String>,
+    // source ranges allowed to reach the admin routes, empty allows any
+    pub admin_allowed: Vec<Cidr>,
+    // default per-key limits, keys can get their own through `/api/keys`
+    pub rate_limit: TenantLimits,
+    pub usage: UsageSettings,
+    pub models: ModelsSettings,
+    pub timeouts: TimeoutSettings,
+    pub lifecycle: LifecycleSettings,
+    // lines below this level are dropped, changeable at runtime through `/api/log-level`
+    pub log_level: Level,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // total tries per request, each on a different backend - 1 disables retries
+    pub max_attempts: u32,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let default_limits = RequestLimits::default();
+        let limits = RequestLimits {
+            max_header_bytes: env_or("LB_MAX_HEADER_BYTES", default_limits.max_header_bytes),
+            max_body_bytes: env_or("LB_MAX_BODY_BYTES", default_limits.max_body_bytes),
+        };
+
+        let default_pool = PoolSettings::default();
+        let pool = PoolSettings {
+            max_idle: env_or("LB_POOL_MAX_IDLE", default_pool.max_idle),
+            idle_timeout: Duration::from_secs(env_or(
+                "LB_POOL_IDLE_TIMEOUT_SECS",
+                default_pool.idle_timeout.as_secs(),
+            )),
+        };
+
+        let default_health = HealthSettings::default();
+        let health = HealthSettings {
+            interval: Duration::from_secs(env_or(
+                "LB_HEALTH_INTERVAL_SECS",
+                default_health.interval.as_secs(),
+            )),
+            timeout: Duration::from_secs(env_or(
+                "LB_HEALTH_TIMEOUT_SECS",
+                default_health.timeout.as_secs(),
+            )),
+            path: env_or("LB_HEALTH_PATH", default_health.path),
+            // zero would flip a service on every probe, whatever its outcome
+            unhealthy_threshold: env_or(
+                "LB_HEALTH_UNHEALTHY_THRESHOLD",
+                default_health.unhealthy_threshold,
+            )
+            .max(1),
+            healthy_threshold: env_or(
+                "LB_HEALTH_HEALTHY_THRESHOLD",
+                default_health.healthy_threshold,
+            )
+            .max(1),
+        };
+
+        let default_outlier = OutlierSettings::default();
+        let outlier = OutlierSettings {
+            // zero would eject a service on its first failure after any success
+            consecutive_errors: env_or(
+                "LB_OUTLIER_CONSECUTIVE_ERRORS",
+                default_outlier.consecutive_errors,
+            )
+            .max(1),
+            base_ejection: Duration::from_secs(env_or(
+                "LB_OUTLIER_BASE_EJECTION_SECS",
+                default_outlier.base_ejection.as_secs(),
+            )),
+            max_ejection: Duration::from_secs(env_or(
+                "LB_OUTLIER_MAX_EJECTION_SECS",
+                default_outlier.max_ejection.as_secs(),
+            )),
+            max_ejection_percent: env_or(
+                "LB_OUTLIER_MAX_EJECTION_PERCENT",
+                default_outlier.max_ejection_percent,
+            ),
+        };
+
+        let default_breaker = BreakerSettings::default();
+        let breaker = BreakerSettings {
+            consecutive_failures: env_or(
+                "LB_BREAKER_CONSECUTIVE_FAILURES",
+                default_breaker.consecutive_failures,
+            ),
+            error_rate_percent: env_or(
+                "LB_BREAKER_ERROR_RATE_PERCENT",
+                default_breaker.error_rate_percent,
+            ),
+            window: env_or("LB_BREAKER_WINDOW", default_breaker.window),
+            min_requests: env_or("LB_BREAKER_MIN_REQUESTS", default_breaker.min_requests),
+            open_duration: Duration::from_secs(env_or(
+                "LB_BREAKER_OPEN_SECS",
+                default_breaker.open_duration.as_secs(),
+            )),
+            half_open_requests: env_or(
+                "LB_BREAKER_HALF_OPEN_REQUESTS",
+                default_breaker.half_open_requests,
+            ),
+        };
+
+        let default_queue = QueueSettings::default();
+        let queue = QueueSettings {
+            max_waiting: env_or("LB_QUEUE_MAX_WAITING", default_queue.max_waiting),
+            timeout: Duration::from_secs(env_or(
+                "LB_QUEUE_TIMEOUT_SECS",
+                default_queue.timeout.as_secs(),
+            )),
+            retry_after: Duration::from_secs(env_or(
+                "LB_QUEUE_RETRY_AFTER_SECS",
+                default_queue.retry_after.as_secs(),
+            )),
+        };
+
+        let default_prefix = PrefixSettings::default();
+        let prefix = PrefixSettings {
+            prefix_bytes: env_or("LB_PREFIX_BYTES", default_prefix.prefix_bytes),
+            load_factor: env_or("LB_PREFIX_LOAD_FACTOR", default_prefix.load_factor),
+            ttl: Duration::from_secs(env_or("LB_PREFIX_TTL_SECS", default_prefix.ttl.as_secs())),
+            max_entries: env_or("LB_PREFIX_MAX_ENTRIES", default_prefix.max_entries),
+        };
+
+        let default_usage = UsageSettings::default();
+        let usage = UsageSettings {
+            file: env::var("LB_USAGE_FILE").ok().filter(|f| !f.is_empty()),
+            flush_interval: Duration::from_secs(env_or(
+                "LB_USAGE_FLUSH_SECS",
+                default_usage.flush_interval.as_secs(),
+            )),
+            retention: Duration::from_secs(
+                env_or(
+                    "LB_USAGE_RETENTION_DAYS",
+                    default_usage.retention.as_secs() / 86_400,
+                ) * 86_400,
+            ),
+        };
+
+        let default_timeouts = TimeoutSettings::default();
+        let timeouts = TimeoutSettings {
+            client_header: Duration::from_secs(env_or(
+                "LB_CLIENT_HEADER_TIMEOUT_SECS",
+                default_timeouts.client_header.as_secs(),
+            )),
+            client_body: Duration::from_secs(env_or(
+                "LB_CLIENT_BODY_TIMEOUT_SECS",
+                default_timeouts.client_body.as_secs(),
+            )),
+            connect: Duration::from_secs(env_or(
+                "LB_CONNECT_TIMEOUT_SECS",
+                default_timeouts.connect.as_secs(),
+            )),
+            first_byte: Duration::from_secs(env_or(
+                "LB_FIRST_BYTE_TIMEOUT_SECS",
+                default_timeouts.first_byte.as_secs(),
+            )),
+            idle: Duration::from_secs(env_or(
+                "LB_STREAM_IDLE_TIMEOUT_SECS",
+                default_timeouts.idle.as_secs(),
+            )),
+            total: Duration::from_secs(env_or(
+                "LB_REQUEST_TIMEOUT_SECS",
+                default_timeouts.total.as_secs(),
+            )),
+            max_override: Duration::from_secs(env_or(
+                "LB_MAX_REQUEST_TIMEOUT_SECS",
+                default_timeouts.max_override.as_secs(),
+            )),
+        };
+
+        let default_lifecycle = LifecycleSettings::default();
+        let lifecycle = LifecycleSettings {
+            probation_requests: env_or(
+                "LB_PROBATION_REQUESTS",
+                default_lifecycle.probation_requests,
+            ),
+            probation_weight_percent: env_or(
+                "LB_PROBATION_WEIGHT_PERCENT",
+                default_lifecycle.probation_weight_percent,
+            ),
+            drain_timeout: Duration::from_secs(env_or(
+                "LB_DRAIN_TIMEOUT_SECS",
+                default_lifecycle.drain_timeout.as_secs(),
+            )),
+        };
+
+        let api_keys_file = env::var("LB_API_KEYS_FILE").ok().filter(|f| !f.is_empty());
+
+        let admin_token = admin_token()?;
+        let admin_allowed = admin_allowed()?;
+
+        Ok(Self {
+            limits,
+            pool,
+            client_idle_timeout: Duration::from_secs(env_or("LB_CLIENT_IDLE_TIMEOUT_SECS", 60)),
+            retry: RetryPolicy {
+                max_attempts: env_or("LB_RETRY_MAX_ATTEMPTS", 3),
+            },
+            health,
+            outlier,
+            breaker,
+            queue,
+            strategy: env_or("LB_STRATEGY", "weighted_random".to_string()),
+            latency: LatencySettings {
+                decay: Duration::from_secs(env_or(
+                    "LB_LATENCY_DECAY_SECS",
+                    LatencySettings::default().decay.as_secs(),
+                )),
+            },
+            session_affinity: env_or("LB_SESSION_AFFINITY", false),
+            prefix,
+            // a keys file implies keys are wanted
+            require_api_key: env_or("LB_REQUIRE_API_KEY", api_keys_file.is_some()),
+            api_keys_file,
+            admin_token,
+            admin_allowed,
+            rate_limit: TenantLimits {
+                requests_per_minute: env_or("LB_RATE_LIMIT_RPM", 0),
+                tokens_per_minute: env_or("LB_RATE_LIMIT_TPM", 0),
+                monthly_tokens: env_or("LB_QUOTA_MONTHLY_TOKENS", 0),
+            },
+            usage,
+            models: ModelsSettings {
+                cache_ttl: Duration::from_secs(env_or(
+                    "LB_MODELS_CACHE_SECS",
+                    ModelsSettings::default().cache_ttl.as_secs(),
+                )),
+                timeout: Duration::from_secs(env_or(
+                    "LB_MODELS_TIMEOUT_SECS",
+                    ModelsSettings::default().timeout.as_secs(),
+                )),
+            },
+            timeouts,
+            lifecycle,
+            log_level: env_or("LB_LOG_LEVEL", log::level()),
+        })
+    }
+}
+
+// the token is given directly or as a file, e.g. a mounted kubernetes secret - either
+// way it must not be empty once it is configured
+fn admin_token() -> Result<Option<String>, String> {
+    let (token, source) = match (env::var("LB_ADMIN_TOKEN"), env::var("LB_ADMIN_TOKEN_FILE")) {
+        (Ok(token), _) => (token, "LB_ADMIN_TOKEN".to_string()),
+        (Err(_), Ok(path)) => {
+            let token = std::fs::read_to_string(&path)
+                .map_err(|e| format!("can't read the admin token from {}: {}", path, e))?;
+            (token, path)
+        }
+        (Err(_), Err(_)) => return Ok(None),
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(format!("the admin token in {} is empty", source));
+    }
+    Ok(Some(token.to_string()))
+}
+
+// a single range that doesn't parse fails the whole list, dropping it could leave
+// nothing and so allow any source
+fn admin_allowed() -> Result<Vec<Cidr>, String> {
+    let Ok(ranges) = env::var("LB_ADMIN_ALLOWED_CIDRS") else {
+        return Ok(Vec::new());
+    };
+    let allowed = ranges
+        .split(',')
+        .filter(|range| !range.trim().is_empty())
+        .map(|range| {
+            range
+                .parse::<Cidr>()
+                .map_err(|e| format!("invalid LB_ADMIN_ALLOWED_CIDRS entry '{}': {}", range, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if allowed.is_empty() {
+        return Err("LB_ADMIN_ALLOWED_CIDRS is set but lists no ranges".to_string());
+    }
+    Ok(allowed)
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                warn!("invalid value for {}: '{}', using default", key, value);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
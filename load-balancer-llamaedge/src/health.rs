@@ -0,0 +1,211 @@
+use crate::http::{self, RequestLimits};
+use crate::time::unix_now;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone)]
+pub struct HealthSettings {
+    // time between probe rounds, zero disables active health checking
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub path: String,
+    // consecutive failed probes before a healthy backend is ejected, at least 1
+    pub unhealthy_threshold: u32,
+    // consecutive successful probes before an ejected backend is taken back, at least 1
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            path: "/v1/models".to_string(),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    pub ok: bool,
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+    // unix timestamp (seconds) of the probe
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthState {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub last_probe: Option<ProbeResult>,
+}
+
+impl Default for HealthState {
+    // a freshly registered backend is trusted until probes say otherwise
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            last_probe: None,
+        }
+    }
+}
+
+impl HealthState {
+    // records a probe, returns the new health if it flipped
+    pub fn record(&mut self, probe: ProbeResult, settings: &HealthSettings) -> Option<bool> {
+        if probe.ok {
+            self.consecutive_successes += 1;
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures += 1;
+            self.consecutive_successes = 0;
+        }
+        self.last_probe = Some(probe);
+
+        if self.healthy && self.consecutive_failures >= settings.unhealthy_threshold {
+            self.healthy = false;
+            return Some(false);
+        }
+        if !self.healthy && self.consecutive_successes >= settings.healthy_threshold {
+            self.healthy = true;
+            return Some(true);
+        }
+        None
+    }
+}
+
+async fn send_probe(
+    address: &str,
+    path: &str,
+    headers: &BTreeMap<String, String>,
+) -> Result<u16, Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(address).await?;
+    let mut probe = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        path, address
+    );
+    http::push_injected_headers(&mut probe, headers);
+    probe.push_str("\r\n");
+    stream.write_all(probe.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    let head = http::read_response_head(&mut stream, &mut buf, &RequestLimits::default()).await?;
+    Ok(head.status)
+}
+
+// probes a backend once with its injected `headers` - any 2xx answer within the timeout
+// counts as healthy
+pub async fn probe(
+    address: &str,
+    headers: &BTreeMap<String, String>,
+    settings: &HealthSettings,
+) -> ProbeResult {
+    let started = Instant::now();
+    let outcome = tokio::time::timeout(
+        settings.timeout,
+        send_probe(address, &settings.path, headers),
+    )
+    .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    let (ok, status, error) = match outcome {
+        Ok(Ok(status)) if (200..300).contains(&status) => (true, Some(status), None),
+        Ok(Ok(status)) => (false, Some(status), Some(format!("http {}", status))),
+        Ok(Err(e)) => (false, None, Some(e.to_string())),
+        Err(_) => (
+            false,
+            None,
+            Some(format!(
+                "timed out after {}ms",
+                settings.timeout.as_millis()
+            )),
+        ),
+    };
+
+    ProbeResult {
+        ok,
+        status,
+        latency_ms,
+        error,
+        at: unix_now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> HealthSettings {
+        HealthSettings {
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+            ..Default::default()
+        }
+    }
+
+    fn probe(ok: bool) -> ProbeResult {
+        ProbeResult {
+            ok,
+            status: ok.then_some(200),
+            latency_ms: 1,
+            error: (!ok).then(|| "connection refused".to_string()),
+            at: 0,
+        }
+    }
+
+    #[test]
+    fn ejected_after_consecutive_failures() {
+        let settings = settings();
+        let mut state = HealthState::default();
+        assert_eq!(state.record(probe(false), &settings), None);
+        assert_eq!(state.record(probe(false), &settings), None);
+        // a success in between starts the count over
+        assert_eq!(state.record(probe(true), &settings), None);
+        assert_eq!(state.record(probe(false), &settings), None);
+        assert_eq!(state.record(probe(false), &settings), None);
+        assert_eq!(state.record(probe(false), &settings), Some(false));
+        assert!(!state.healthy);
+        // already unhealthy, no further flip
+        assert_eq!(state.record(probe(false), &settings), None);
+        assert_eq!(state.last_probe.as_ref().map(|p| p.ok), Some(false));
+    }
+
+    #[test]
+    fn taken_back_after_consecutive_successes() {
+        let settings = settings();
+        let mut state = HealthState {
+            healthy: false,
+            ..Default::default()
+        };
+        assert_eq!(state.record(probe(true), &settings), None);
+        assert_eq!(state.record(probe(false), &settings), None);
+        assert_eq!(state.record(probe(true), &settings), None);
+        assert_eq!(state.record(probe(true), &settings), Some(true));
+        assert!(state.healthy);
+        assert_eq!(state.record(probe(true), &settings), None);
+    }
+
+    #[test]
+    fn thresholds_of_one_flip_on_every_change() {
+        let settings = HealthSettings {
+            unhealthy_threshold: 1,
+            healthy_threshold: 1,
+            ..Default::default()
+        };
+        let mut state = HealthState::default();
+        assert_eq!(state.record(probe(true), &settings), None);
+        assert_eq!(state.record(probe(false), &settings), Some(false));
+        assert_eq!(state.record(probe(true), &settings), Some(true));
+        assert_eq!(state.record(probe(true), &settings), None);
+    }
+}
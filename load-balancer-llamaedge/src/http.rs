@@ -0,0 +1,1061 @@
+use crate::timeouts::{RequestTimeouts, TimeoutKind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// upper bound for a single chunk-size line (size + extensions) in a chunked body
+const MAX_CHUNK_LINE_BYTES: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(std::io::Error),
+    // client closed the connection before sending anything
+    ConnectionClosed,
+    // client closed the connection in the middle of a request
+    UnexpectedEof,
+    HeadersTooLarge,
+    BodyTooLarge,
+    InvalidRequestLine(String),
+    InvalidHeader(String),
+    UnsupportedVersion(String),
+    InvalidContentLength(String),
+    // both content-length and transfer-encoding were sent
+    AmbiguousLength,
+    UnsupportedTransferEncoding(String),
+    InvalidChunk,
+    UnsupportedExpectation(String),
+    // backend sent something that is not an http/1.x status line
+    InvalidStatusLine(String),
+    // the client or backend took longer than the timeout allows
+    Timeout(TimeoutKind),
+}
+
+impl ParseError {
+    // status line to answer with, `None` when the client is gone anyway
+    pub fn status(&self) -> Option<&'static str> {
+        match self {
+            ParseError::Io(_) | ParseError::ConnectionClosed | ParseError::UnexpectedEof => None,
+            ParseError::HeadersTooLarge => Some("431 Request Header Fields Too Large"),
+            ParseError::BodyTooLarge => Some("413 Payload Too Large"),
+            ParseError::UnsupportedVersion(_) => Some("505 HTTP Version Not Supported"),
+            ParseError::UnsupportedTransferEncoding(_) => Some("501 Not Implemented"),
+            ParseError::UnsupportedExpectation(_) => Some("417 Expectation Failed"),
+            ParseError::InvalidStatusLine(_) => Some("502 Bad Gateway"),
+            ParseError::Timeout(TimeoutKind::ClientHeader | TimeoutKind::ClientBody) => {
+                Some("408 Request Timeout")
+            }
+            ParseError::Timeout(_) => Some("504 Gateway Timeout"),
+            ParseError::InvalidRequestLine(_)
+            | ParseError::InvalidHeader(_)
+            | ParseError::InvalidContentLength(_)
+            | ParseError::AmbiguousLength
+            | ParseError::InvalidChunk => Some("400 Bad Request"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "io error: {}", e),
+            ParseError::ConnectionClosed => write!(f, "connection closed before request"),
+            ParseError::UnexpectedEof => write!(f, "connection closed mid-request"),
+            ParseError::HeadersTooLarge => write!(f, "request headers exceed limit"),
+            ParseError::BodyTooLarge => write!(f, "request body exceeds limit"),
+            ParseError::InvalidRequestLine(line) => write!(f, "invalid request line: {}", line),
+            ParseError::InvalidHeader(line) => write!(f, "invalid header: {}", line),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported http version: {}", v),
+            ParseError::InvalidContentLength(v) => write!(f, "invalid content-length: {}", v),
+            ParseError::AmbiguousLength => {
+                write!(f, "both content-length and transfer-encoding present")
+            }
+            ParseError::UnsupportedTransferEncoding(v) => {
+                write!(f, "unsupported transfer-encoding: {}", v)
+            }
+            ParseError::InvalidChunk => write!(f, "malformed chunked body"),
+            ParseError::UnsupportedExpectation(v) => write!(f, "unsupported expectation: {}", v),
+            ParseError::InvalidStatusLine(line) => write!(f, "invalid status line: {}", line),
+            ParseError::Timeout(kind) => write!(f, "{} timeout", kind),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<std::io::Error> for ParseError {
+    fn from(e: std::io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    // always the decoded body - chunked requests are de-chunked while reading
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    // http/1.1 connections are persistent unless the client asks to close,
+    // http/1.0 keep-alive is not supported
+    pub fn wants_keep_alive(&self) -> bool {
+        self.version == "HTTP/1.1"
+            && !self.header("connection").is_some_and(|c| {
+                c.split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("close"))
+            })
+    }
+
+    // request line + headers for forwarding upstream, re-framed with a content-length
+    // since the body has already been fully read (and possibly de-chunked), hop-by-hop
+    // headers only apply to the client hop and are dropped
+    pub fn head_bytes(&self, forwarding: &Forwarding) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.method, self.path, self.version);
+        let listed = connection_listed(&self.headers);
+        for (name, value) in &self.headers {
+            if is_framing_header(name)
+                || is_hop_by_hop(name)
+                || listed.iter().any(|l| l.eq_ignore_ascii_case(name))
+                || is_rewritten_header(name)
+                || forwarding
+                    .headers
+                    .keys()
+                    .any(|injected| injected.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            push_header(&mut head, name, value);
+        }
+
+        push_header(&mut head, "Host", forwarding.host);
+        let client_ip = forwarding.client_ip.to_string();
+        let forwarded_for = match self.header("x-forwarded-for") {
+            Some(earlier) => format!("{}, {}", earlier, client_ip),
+            None => client_ip,
+        };
+        push_header(&mut head, "X-Forwarded-For", &forwarded_for);
+        // kept when a proxy in front of the balancer already set them, e.g. after tls termination
+        push_header(
+            &mut head,
+            "X-Forwarded-Proto",
+            self.header("x-forwarded-proto").unwrap_or("http"),
+        );
+        if let Some(host) = self.header("x-forwarded-host").or(self.header("host")) {
+            push_header(&mut head, "X-Forwarded-Host", host);
+        }
+        let via = match self.header("via") {
+            Some(earlier) => format!("{}, {}", earlier, VIA),
+            None => VIA.to_string(),
+        };
+        push_header(&mut head, "Via", &via);
+        for (name, value) in forwarding.headers {
+            push_header(&mut head, name, value);
+        }
+
+        if !self.body.is_empty() || method_has_body(&self.method) {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+// complete response with a json body
+pub fn json_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// like `json_response`, with extra headers such as `Retry-After`
+pub fn json_response_with_headers(status: &str, body: &str, headers: &[(&str, String)]) -> String {
+    let extra: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\n\r\n{}",
+        status,
+        extra,
+        body.len(),
+        body
+    )
+}
+
+// error body in the shape openai-compatible clients expect
+pub fn openai_error(
+    message: &str,
+    error_type: &str,
+    param: Option<&str>,
+    code: Option<&str>,
+) -> String {
+    serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "param": param,
+            "code": code,
+        }
+    })
+    .to_string()
+}
+
+// complete response with a plain-text body
+pub fn text_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// value of `name` in a `a=1&b=2` query string, values are taken as they are
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+pub fn empty_response(status: &str) -> String {
+    format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status)
+}
+
+// headers about a single connection rather than the message (rfc 9110 7.6.1)
+fn is_hop_by_hop(name: &str) -> bool {
+    [
+        "connection",
+        "keep-alive",
+        "proxy-connection",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "te",
+        "trailer",
+        "transfer-encoding",
+        "upgrade",
+    ]
+    .iter()
+    .any(|hop| name.eq_ignore_ascii_case(hop))
+}
+
+// headers the sender marked as hop-by-hop by naming them in `Connection`
+fn connection_listed(headers: &[(String, String)]) -> Vec<&str> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+// set by the balancer on forwarded requests in place of the client's
+fn is_rewritten_header(name: &str) -> bool {
+    [
+        "host",
+        "x-forwarded-for",
+        "x-forwarded-proto",
+        "x-forwarded-host",
+        "via",
+    ]
+    .iter()
+    .any(|rewritten| name.eq_ignore_ascii_case(rewritten))
+}
+
+// adds a service's injected headers to a request the balancer makes on its own, such as
+// a health probe, so a backend that wants its api key answers those as well
+pub fn push_injected_headers(head: &mut String, headers: &BTreeMap<String, String>) {
+    for (name, value) in headers {
+        if is_hop_by_hop(name) || is_rewritten_header(name) {
+            continue;
+        }
+        push_header(head, name, value);
+    }
+}
+
+fn push_header(head: &mut String, name: &str, value: &str) {
+    head.push_str(name);
+    head.push_str(": ");
+    head.push_str(value);
+    head.push_str("\r\n");
+}
+
+fn is_framing_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case("content-length")
+        || name.eq_ignore_ascii_case("transfer-encoding")
+        || name.eq_ignore_ascii_case("expect")
+}
+
+fn method_has_body(method: &str) -> bool {
+    matches!(method, "POST" | "PUT" | "PATCH")
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+// append whatever the stream has to `buf`, returns number of bytes read (0 on EOF)
+async fn fill<S>(stream: &mut S, buf: &mut Vec<u8>) -> Result<usize, ParseError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut temp_buf = [0; 8192];
+    let bytes_read = stream.read(&mut temp_buf).await?;
+    buf.extend_from_slice(&temp_buf[..bytes_read]);
+    Ok(bytes_read)
+}
+
+enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+fn parse_head(head: &str) -> Result<HttpRequest, ParseError> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
+        return Err(ParseError::InvalidRequestLine(request_line.to_string()));
+    }
+    let version = parts[2];
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(ParseError::UnsupportedVersion(version.to_string()));
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        // obsolete line folding is not allowed in requests (rfc 9112 5.2)
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return Err(ParseError::InvalidHeader(line.to_string()));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| ParseError::InvalidHeader(line.to_string()))?;
+        if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+            return Err(ParseError::InvalidHeader(line.to_string()));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    Ok(HttpRequest {
+        method: parts[0].to_string(),
+        path: parts[1].to_string(),
+        version: version.to_string(),
+        headers,
+        body: Vec::new(),
+    })
+}
+
+fn body_framing(request: &HttpRequest) -> Result<BodyFraming, ParseError> {
+    let mut content_length: Option<usize> = None;
+    let mut transfer_encoding: Option<String> = None;
+
+    for (name, value) in &request.headers {
+        if name.eq_ignore_ascii_case("content-length") {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::InvalidContentLength(value.clone()));
+            }
+            let length: usize = value
+                .parse()
+                .map_err(|_| ParseError::InvalidContentLength(value.clone()))?;
+            // repeated content-length headers must agree
+            if content_length.is_some_and(|existing| existing != length) {
+                return Err(ParseError::InvalidContentLength(value.clone()));
+            }
+            content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            let combined = match transfer_encoding.take() {
+                Some(existing) => format!("{}, {}", existing, value),
+                None => value.clone(),
+            };
+            transfer_encoding = Some(combined);
+        }
+    }
+
+    match (transfer_encoding, content_length) {
+        (Some(_), Some(_)) => Err(ParseError::AmbiguousLength),
+        (Some(te), None) => {
+            // only plain chunked is supported, compressed request bodies are not
+            let codings: Vec<String> = te
+                .split(',')
+                .map(|c| c.trim().to_ascii_lowercase())
+                .filter(|c| !c.is_empty())
+                .collect();
+            if codings.len() == 1 && codings[0] == "chunked" {
+                Ok(BodyFraming::Chunked)
+            } else {
+                Err(ParseError::UnsupportedTransferEncoding(te))
+            }
+        }
+        (None, Some(0)) | (None, None) => Ok(BodyFraming::Empty),
+        (None, Some(length)) => Ok(BodyFraming::Length(length)),
+    }
+}
+
+// reads the head of the next http/1.1 request from the stream, the body is read with
+// `read_request_body` - a body that is too large or can't be framed fails here already
+//
+// `buf` carries bytes that were read from the stream but not consumed yet - anything past
+// the end of the head is left in it
+// `header_timeout` runs from the call until the headers are complete, so a client
+// trickling its headers in can't hold the connection open
+pub async fn read_request_head<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    limits: &RequestLimits,
+    header_timeout: Option<Duration>,
+) -> Result<HttpRequest, ParseError>
+where
+    S: AsyncRead + Unpin,
+{
+    let header_deadline = header_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+
+    // read until end of headers
+    let head_end = loop {
+        // tolerate empty lines in front of the request line (rfc 9112 2.2)
+        while buf.starts_with(b"\r\n") {
+            buf.drain(..2);
+        }
+
+        if let Some(pos) = find_head_end(buf) {
+            if pos + 4 > limits.max_header_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            break pos;
+        }
+        if buf.len() > limits.max_header_bytes {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        let filled = match header_deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, fill(stream, buf))
+                .await
+                .map_err(|_| ParseError::Timeout(TimeoutKind::ClientHeader))??,
+            None => fill(stream, buf).await?,
+        };
+        if filled == 0 {
+            return Err(if buf.is_empty() {
+                ParseError::ConnectionClosed
+            } else {
+                ParseError::UnexpectedEof
+            });
+        }
+    };
+
+    let head = std::str::from_utf8(&buf[..head_end])
+        .map_err(|_| ParseError::InvalidHeader("non utf-8 header data".to_string()))?;
+    let request = parse_head(head)?;
+    buf.drain(..head_end + 4);
+
+    let framing = body_framing(&request)?;
+    if let BodyFraming::Length(length) = framing
+        && length > limits.max_body_bytes
+    {
+        return Err(ParseError::BodyTooLarge);
+    }
+    if let Some(expect) = request.header("expect")
+        && !expect.eq_ignore_ascii_case("100-continue")
+    {
+        return Err(ParseError::UnsupportedExpectation(expect.to_string()));
+    }
+    Ok(request)
+}
+
+// reads the (de-chunked) body of a request whose head `read_request_head` returned,
+// leaving anything past its end in `buf`
+pub async fn read_request_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    request: &mut HttpRequest,
+    limits: &RequestLimits,
+) -> Result<(), ParseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let framing = body_framing(request)?;
+    // `Expect: 100-continue` - only ask for the body if there is one and the client has
+    // not started sending it
+    if request.header("expect").is_some()
+        && !matches!(framing, BodyFraming::Empty)
+        && buf.is_empty()
+    {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        stream.flush().await?;
+    }
+
+    request.body = match framing {
+        BodyFraming::Empty => Vec::new(),
+        BodyFraming::Length(length) => {
+            while buf.len() < length {
+                if fill(stream, buf).await? == 0 {
+                    return Err(ParseError::UnexpectedEof);
+                }
+            }
+            buf.drain(..length).collect()
+        }
+        BodyFraming::Chunked => read_chunked_body(stream, buf, limits).await?,
+    };
+    Ok(())
+}
+
+async fn read_chunked_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    limits: &RequestLimits,
+) -> Result<Vec<u8>, ParseError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(stream, buf, MAX_CHUNK_LINE_BYTES).await?;
+        let size = parse_chunk_size(&line)?;
+
+        if size == 0 {
+            // skip trailer fields up to the terminating empty line
+            let mut trailer_bytes = 0;
+            loop {
+                let trailer = read_line(stream, buf, limits.max_header_bytes).await?;
+                if trailer.is_empty() {
+                    return Ok(body);
+                }
+                trailer_bytes += trailer.len() + 2;
+                if trailer_bytes > limits.max_header_bytes {
+                    return Err(ParseError::HeadersTooLarge);
+                }
+            }
+        }
+
+        if body.len().saturating_add(size) > limits.max_body_bytes {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        // chunk data followed by crlf
+        while buf.len() < size + 2 {
+            if fill(stream, buf).await? == 0 {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+        if &buf[size..size + 2] != b"\r\n" {
+            return Err(ParseError::InvalidChunk);
+        }
+        body.extend_from_slice(&buf[..size]);
+        buf.drain(..size + 2);
+    }
+}
+
+fn parse_chunk_size(line: &str) -> Result<usize, ParseError> {
+    // chunk extensions are ignored
+    let size_str = line.split(';').next().unwrap_or("").trim();
+    usize::from_str_radix(size_str, 16).map_err(|_| ParseError::InvalidChunk)
+}
+
+// reads a single crlf-terminated line, consuming it (and the crlf) from `buf`
+async fn read_line<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<String, ParseError>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(pos) = find_crlf(buf) {
+            if pos > max_len {
+                return Err(ParseError::InvalidChunk);
+            }
+            let line =
+                String::from_utf8(buf[..pos].to_vec()).map_err(|_| ParseError::InvalidChunk)?;
+            buf.drain(..pos + 2);
+            return Ok(line);
+        }
+        if buf.len() > max_len {
+            return Err(ParseError::InvalidChunk);
+        }
+        if fill(stream, buf).await? == 0 {
+            return Err(ParseError::UnexpectedEof);
+        }
+    }
+}
+
+// `Via` entry the balancer adds to forwarded requests
+const VIA: &str = "1.1 llamaedge-lb";
+
+// what the balancer changes about a request it forwards to a backend and its response
+pub struct Forwarding<'a> {
+    // backend address the `Host` header is rewritten to
+    pub host: &'a str,
+    // added to `X-Forwarded-For`
+    pub client_ip: IpAddr,
+    // set on every request to the backend, replacing the client's headers of the same name
+    pub headers: &'a BTreeMap<String, String>,
+    // added to the response relayed to the client, such as `X-Served-By`
+    pub response_headers: &'a [(&'static str, String)],
+    // how long the backend gets to answer
+    pub timeouts: &'a RequestTimeouts,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyKind {
+    Empty,
+    Length(usize),
+    Chunked,
+    // no framing - body ends when the backend closes the connection
+    UntilClose,
+}
+
+impl ResponseHead {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // whether the backend is willing to serve another request on this connection
+    pub fn allows_reuse(&self) -> bool {
+        self.version == "HTTP/1.1"
+            && !self.header("connection").is_some_and(|c| {
+                c.split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("close"))
+            })
+    }
+
+    pub fn is_event_stream(&self) -> bool {
+        self.header("content-type")
+            .is_some_and(|ct| ct.trim_start().starts_with("text/event-stream"))
+    }
+
+    // how the body following this head is delimited (rfc 9112 6.3)
+    pub fn body_kind(&self, request_method: &str) -> BodyKind {
+        if request_method == "HEAD"
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            return BodyKind::Empty;
+        }
+        if let Some(te) = self.header("transfer-encoding") {
+            let last = te.rsplit(',').next().unwrap_or("").trim();
+            return if last.eq_ignore_ascii_case("chunked") {
+                BodyKind::Chunked
+            } else {
+                BodyKind::UntilClose
+            };
+        }
+        match self.header("content-length").map(|v| v.parse::<usize>()) {
+            Some(Ok(length)) => BodyKind::Length(length),
+            _ => BodyKind::UntilClose,
+        }
+    }
+
+    // status line + headers for the client, with the `Connection` header replaced
+    // and `extra` headers added by the load balancer - `Transfer-Encoding` is the only
+    // hop-by-hop header kept since the body is relayed with the backend's framing
+    pub fn head_bytes(&self, connection: &str, extra: &[(&str, String)]) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+        let listed = connection_listed(&self.headers);
+        for (name, value) in &self.headers {
+            let hop_by_hop = is_hop_by_hop(name) && !name.eq_ignore_ascii_case("transfer-encoding");
+            if hop_by_hop
+                || listed.iter().any(|l| l.eq_ignore_ascii_case(name))
+                || extra.iter().any(|(e, _)| e.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            push_header(&mut head, name, value);
+        }
+        for (name, value) in extra {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Connection: {}\r\n\r\n", connection));
+        head.into_bytes()
+    }
+}
+
+fn parse_response_head(head: &str) -> Result<ResponseHead, ParseError> {
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status = parts.next().and_then(|s| s.parse::<u16>().ok());
+    let reason = parts.next().unwrap_or("");
+    let status = match status {
+        Some(status) if version.starts_with("HTTP/1.") => status,
+        _ => return Err(ParseError::InvalidStatusLine(status_line.to_string())),
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| ParseError::InvalidHeader(line.to_string()))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(ResponseHead {
+        version: version.to_string(),
+        status,
+        reason: reason.to_string(),
+        headers,
+    })
+}
+
+// reads a backend response head, skipping interim 1xx responses
+pub async fn read_response_head<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    limits: &RequestLimits,
+) -> Result<ResponseHead, ParseError>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let head_end = loop {
+            if let Some(pos) = find_head_end(buf) {
+                break pos;
+            }
+            if buf.len() > limits.max_header_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            if fill(stream, buf).await? == 0 {
+                return Err(if buf.is_empty() {
+                    ParseError::ConnectionClosed
+                } else {
+                    ParseError::UnexpectedEof
+                });
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+        buf.drain(..head_end + 4);
+        let response = parse_response_head(&head)?;
+        if (100..200).contains(&response.status) && response.status != 101 {
+            continue;
+        }
+        return Ok(response);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DecodeState {
+    Length(usize),
+    ChunkSize,
+    ChunkData(usize),
+    ChunkDataEnd,
+    Trailers,
+    UntilClose,
+    Done,
+}
+
+// incremental body decoder - hands out body data as soon as it arrives
+//
+// all state lives in the decoder and `buf`, so a pending `next` can be dropped
+// (e.g. in a `select!`) and called again without losing data
+pub struct BodyDecoder {
+    state: DecodeState,
+}
+
+impl BodyDecoder {
+    pub fn new(kind: BodyKind) -> Self {
+        let state = match kind {
+            BodyKind::Empty | BodyKind::Length(0) => DecodeState::Done,
+            BodyKind::Length(length) => DecodeState::Length(length),
+            BodyKind::Chunked => DecodeState::ChunkSize,
+            BodyKind::UntilClose => DecodeState::UntilClose,
+        };
+        Self { state }
+    }
+
+    // next piece of decoded body data, `None` once the body is complete
+    pub async fn next<S>(
+        &mut self,
+        stream: &mut S,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, ParseError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        loop {
+            match self.state {
+                DecodeState::Done => return Ok(None),
+                DecodeState::Length(remaining) | DecodeState::ChunkData(remaining) => {
+                    if buf.is_empty() && fill(stream, buf).await? == 0 {
+                        return Err(ParseError::UnexpectedEof);
+                    }
+                    let n = remaining.min(buf.len());
+                    let left = remaining - n;
+                    self.state = match self.state {
+                        DecodeState::Length(_) if left == 0 => DecodeState::Done,
+                        DecodeState::Length(_) => DecodeState::Length(left),
+                        _ if left == 0 => DecodeState::ChunkDataEnd,
+                        _ => DecodeState::ChunkData(left),
+                    };
+                    return Ok(Some(buf.drain(..n).collect()));
+                }
+                DecodeState::ChunkSize => {
+                    let line = read_line(stream, buf, MAX_CHUNK_LINE_BYTES).await?;
+                    self.state = match parse_chunk_size(&line)? {
+                        0 => DecodeState::Trailers,
+                        size => DecodeState::ChunkData(size),
+                    };
+                }
+                DecodeState::ChunkDataEnd => {
+                    while buf.len() < 2 {
+                        if fill(stream, buf).await? == 0 {
+                            return Err(ParseError::UnexpectedEof);
+                        }
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    buf.drain(..2);
+                    self.state = DecodeState::ChunkSize;
+                }
+                DecodeState::Trailers => {
+                    if read_line(stream, buf, MAX_CHUNK_LINE_BYTES)
+                        .await?
+                        .is_empty()
+                    {
+                        self.state = DecodeState::Done;
+                    }
+                }
+                DecodeState::UntilClose => {
+                    if buf.is_empty() && fill(stream, buf).await? == 0 {
+                        self.state = DecodeState::Done;
+                        return Ok(None);
+                    }
+                    return Ok(Some(std::mem::take(buf)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RequestLimits {
+        RequestLimits {
+            max_header_bytes: 1024,
+            max_body_bytes: 16,
+        }
+    }
+
+    // feeds `input` to `read_request_head` and `read_request_body` as if a client sent it
+    // and then closed its side, returns the request and whatever was left over in the buffer
+    async fn read(input: &[u8]) -> Result<(HttpRequest, Vec<u8>), ParseError> {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(input).await.unwrap();
+        drop(client);
+        let mut buf = Vec::new();
+        let mut request = read_request_head(&mut server, &mut buf, &limits(), None).await?;
+        read_request_body(&mut server, &mut buf, &mut request, &limits()).await?;
+        Ok((request, buf))
+    }
+
+    async fn decode(kind: BodyKind, input: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let mut stream = input;
+        let mut buf = Vec::new();
+        let mut decoder = BodyDecoder::new(kind);
+        let mut body = Vec::new();
+        while let Some(data) = decoder.next(&mut stream, &mut buf).await? {
+            body.extend_from_slice(&data);
+        }
+        Ok(body)
+    }
+
+    #[tokio::test]
+    async fn content_length_body_leaves_pipelined_bytes() {
+        let (request, rest) = read(
+            b"POST /v1/chat/completions HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1",
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/chat/completions");
+        assert_eq!(request.body, b"hello");
+        assert_eq!(rest, b"GET / HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn request_without_body() {
+        let (request, rest) = read(b"\r\nGET /v1/models HTTP/1.1\r\nHost: lb\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(request.body.is_empty());
+        assert_eq!(request.header("HOST"), Some("lb"));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn chunked_body_with_extensions_and_trailers() {
+        let (request, rest) = read(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;name=value\r\nhello\r\n1\r\n \r\n5\r\nworld\r\n0\r\nX-Trailer: yes\r\n\r\nnext",
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(rest, b"next");
+    }
+
+    #[tokio::test]
+    async fn oversize_bodies_are_rejected() {
+        let declared = read(b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n").await;
+        assert!(matches!(declared, Err(ParseError::BodyTooLarge)));
+        let chunked = read(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n10\r\n0123456789abcdef\r\n1\r\nx\r\n0\r\n\r\n",
+        )
+        .await;
+        assert!(matches!(chunked, Err(ParseError::BodyTooLarge)));
+        let headers =
+            read(&[b"GET / HTTP/1.1\r\nX-Big: ".as_slice(), &[b'a'; 2048]].concat()).await;
+        assert!(matches!(headers, Err(ParseError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn truncated_input() {
+        assert!(matches!(read(b"").await, Err(ParseError::ConnectionClosed)));
+        assert!(matches!(
+            read(b"GET / HTTP/1.1\r\nHost: lb\r\n").await,
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello").await,
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel").await,
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n").await,
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_framing() {
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .await,
+            Err(ParseError::AmbiguousLength)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n").await,
+            Err(ParseError::InvalidContentLength(_))
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nContent-Length: +1\r\n\r\n").await,
+            Err(ParseError::InvalidContentLength(_))
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").await,
+            Err(ParseError::UnsupportedTransferEncoding(_))
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").await,
+            Err(ParseError::InvalidChunk)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n")
+                .await,
+            Err(ParseError::InvalidChunk)
+        ));
+    }
+
+    #[tokio::test]
+    async fn decodes_response_bodies() {
+        assert_eq!(
+            decode(BodyKind::Length(5), b"helloextra").await.unwrap(),
+            b"hello"
+        );
+        assert_eq!(
+            decode(
+                BodyKind::Chunked,
+                b"6;ext=1\r\nhello \r\n5\r\nworld\r\n0\r\nX-Trailer: yes\r\n\r\n"
+            )
+            .await
+            .unwrap(),
+            b"hello world"
+        );
+        assert_eq!(
+            decode(BodyKind::UntilClose, b"until close").await.unwrap(),
+            b"until close"
+        );
+        assert!(
+            decode(BodyKind::Empty, b"ignored")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn decoder_reports_truncated_bodies() {
+        assert!(matches!(
+            decode(BodyKind::Length(10), b"hello").await,
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            decode(BodyKind::Chunked, b"5\r\nhello\r\n").await,
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            decode(BodyKind::Chunked, b"5\r\nhelloXX0\r\n\r\n").await,
+            Err(ParseError::InvalidChunk)
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct LatencySettings {
+    // how quickly older samples lose their influence, a sample this old counts ~37%
+    pub decay: Duration,
+}
+
+impl Default for LatencySettings {
+    fn default() -> Self {
+        Self {
+            decay: Duration::from_secs(10),
+        }
+    }
+}
+
+// peak-sensitive moving average: a slower sample is taken over at once,
+// faster samples only pull the average down gradually
+#[derive(Debug)]
+struct PeakEwma {
+    value_ms: f64,
+    updated: Instant,
+}
+
+impl PeakEwma {
+    fn new(sample_ms: f64) -> Self {
+        Self {
+            value_ms: sample_ms,
+            updated: Instant::now(),
+        }
+    }
+
+    fn observe(&mut self, sample_ms: f64, settings: &LatencySettings) {
+        let now = Instant::now();
+        if sample_ms > self.value_ms || settings.decay.is_zero() {
+            self.value_ms = sample_ms;
+        } else {
+            let elapsed = now.duration_since(self.updated).as_secs_f64();
+            let keep = (-elapsed / settings.decay.as_secs_f64()).exp();
+            self.value_ms = self.value_ms * keep + sample_ms * (1.0 - keep);
+        }
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LatencyState {
+    ttfb: Option<PeakEwma>,
+    total: Option<PeakEwma>,
+    samples: u64,
+}
+
+// latency averages as listed by `GET /api/services` and seen by the balancing strategies
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyStatus {
+    pub ttfb_ms: f64,
+    pub total_ms: f64,
+    pub samples: u64,
+}
+
+fn observe(ewma: &mut Option<PeakEwma>, sample: Duration, settings: &LatencySettings) {
+    let sample_ms = sample.as_secs_f64() * 1000.0;
+    match ewma {
+        Some(ewma) => ewma.observe(sample_ms, settings),
+        None => *ewma = Some(PeakEwma::new(sample_ms)),
+    }
+}
+
+impl LatencyState {
+    // `total` is `None` when the response was cut short and its duration means nothing
+    pub fn record(&mut self, ttfb: Duration, total: Option<Duration>, settings: &LatencySettings) {
+        observe(&mut self.ttfb, ttfb, settings);
+        if let Some(total) = total {
+            observe(&mut self.total, total, settings);
+        }
+        self.samples += 1;
+    }
+
+    // `None` until the service answered at least once
+    pub fn status(&self) -> Option<LatencyStatus> {
+        let ttfb = self.ttfb.as_ref()?;
+        Some(LatencyStatus {
+            ttfb_ms: ttfb.value_ms,
+            total_ms: self.total.as_ref().map_or(ttfb.value_ms, |t| t.value_ms),
+            samples: self.samples,
+        })
+    }
+}
@@ -0,0 +1,292 @@
+use crate::time::unix_now;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// transitions kept per service for `GET /api/services`
+const HISTORY_LEN: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct LifecycleSettings {
+    // successful requests an enabled service handles on probation before it is active,
+    // zero makes enabled services active straight away
+    pub probation_requests: u32,
+    // share of its weight a service on probation is balanced with
+    pub probation_weight_percent: u32,
+    // a draining service is removed after this even with requests still in flight,
+    // zero waits for them however long they take
+    pub drain_timeout: Duration,
+}
+
+impl Default for LifecycleSettings {
+    fn default() -> Self {
+        Self {
+            probation_requests: 20,
+            probation_weight_percent: 10,
+            drain_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+// where a service is in its lifecycle, changed through `POST /api/services/{name}/{action}`
+//
+// active -> draining -> (removed once idle), active <-> disabled, disabled -> probation -> active
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lifecycle {
+    // balanced like any other service
+    Active,
+    // gets no new requests, removed once the ones in flight are done
+    Draining,
+    // gets no requests but stays registered and health checked
+    Disabled,
+    // gets a reduced share of requests until enough of them succeeded
+    Probation,
+}
+
+impl Lifecycle {
+    pub fn name(self) -> &'static str {
+        match self {
+            Lifecycle::Active => "active",
+            Lifecycle::Draining => "draining",
+            Lifecycle::Disabled => "disabled",
+            Lifecycle::Probation => "probation",
+        }
+    }
+
+    // whether new requests may be sent to a service in this state
+    pub fn accepts_requests(self) -> bool {
+        matches!(self, Lifecycle::Active | Lifecycle::Probation)
+    }
+}
+
+pub const LIFECYCLE_STATES: &[Lifecycle] = &[
+    Lifecycle::Active,
+    Lifecycle::Draining,
+    Lifecycle::Disabled,
+    Lifecycle::Probation,
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleTransition {
+    pub from: Lifecycle,
+    pub to: Lifecycle,
+    pub reason: String,
+    // unix timestamp (seconds) of the transition
+    pub at: u64,
+}
+
+#[derive(Debug)]
+pub struct LifecycleState {
+    state: Lifecycle,
+    since: Instant,
+    // successful requests since going on probation
+    probation_successes: u32,
+    history: VecDeque<LifecycleTransition>,
+}
+
+// lifecycle as listed by `GET /api/services`
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleStatus {
+    pub state: Lifecycle,
+    pub since_secs: u64,
+    pub probation_successes: u32,
+    pub transitions: Vec<LifecycleTransition>,
+}
+
+impl Default for LifecycleState {
+    fn default() -> Self {
+        Self {
+            state: Lifecycle::Active,
+            since: Instant::now(),
+            probation_successes: 0,
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl LifecycleState {
+    pub fn state(&self) -> Lifecycle {
+        self.state
+    }
+
+    // moves to `to`, returns the previous state or `None` if the service was already there
+    pub fn transition(&mut self, to: Lifecycle, reason: String) -> Option<Lifecycle> {
+        if self.state == to {
+            return None;
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        let from = self.state;
+        self.history.push_back(LifecycleTransition {
+            from,
+            to,
+            reason,
+            at: unix_now(),
+        });
+        self.state = to;
+        self.since = Instant::now();
+        self.probation_successes = 0;
+        Some(from)
+    }
+
+    // the state an enabled service starts in
+    pub fn enabled_state(settings: &LifecycleSettings) -> Lifecycle {
+        if settings.probation_requests == 0 {
+            Lifecycle::Active
+        } else {
+            Lifecycle::Probation
+        }
+    }
+
+    // feeds the result of a proxied request into probation, returns true when it
+    // made the service active
+    pub fn record(&mut self, failed: bool, settings: &LifecycleSettings) -> bool {
+        if self.state != Lifecycle::Probation {
+            return false;
+        }
+        if failed {
+            // probation starts over, outlier detection and the breaker deal with the failure
+            self.probation_successes = 0;
+            return false;
+        }
+        self.probation_successes += 1;
+        if self.probation_successes < settings.probation_requests {
+            return false;
+        }
+        let reason = format!(
+            "{} successful requests on probation",
+            self.probation_successes
+        );
+        self.transition(Lifecycle::Active, reason);
+        true
+    }
+
+    // whether a draining service has run out of time for its requests in flight
+    pub fn drain_expired(&self, settings: &LifecycleSettings) -> bool {
+        self.state == Lifecycle::Draining
+            && !settings.drain_timeout.is_zero()
+            && self.since.elapsed() >= settings.drain_timeout
+    }
+
+    // the weight a service is balanced with in this state
+    pub fn effective_weight(&self, weight: u32, settings: &LifecycleSettings) -> u32 {
+        if self.state != Lifecycle::Probation || weight == 0 {
+            return weight;
+        }
+        // in u64, a large weight times the percentage does not fit in u32
+        let scaled = u64::from(weight) * u64::from(settings.probation_weight_percent) / 100;
+        scaled.clamp(1, u64::from(u32::MAX)) as u32
+    }
+
+    pub fn status(&self) -> LifecycleStatus {
+        LifecycleStatus {
+            state: self.state,
+            since_secs: self.since.elapsed().as_secs(),
+            probation_successes: self.probation_successes,
+            transitions: self.history.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probation(settings: &LifecycleSettings) -> LifecycleState {
+        let mut state = LifecycleState::default();
+        state.transition(
+            LifecycleState::enabled_state(settings),
+            "enable".to_string(),
+        );
+        state
+    }
+
+    #[test]
+    fn transition_records_history() {
+        let mut state = LifecycleState::default();
+        assert_eq!(
+            state.transition(Lifecycle::Active, "noop".to_string()),
+            None
+        );
+        assert_eq!(
+            state.transition(Lifecycle::Draining, "drain".to_string()),
+            Some(Lifecycle::Active)
+        );
+        for i in 0..HISTORY_LEN * 2 {
+            let to = if i % 2 == 0 {
+                Lifecycle::Disabled
+            } else {
+                Lifecycle::Active
+            };
+            state.transition(to, format!("step {}", i));
+        }
+        let status = state.status();
+        assert_eq!(status.transitions.len(), HISTORY_LEN);
+        assert_eq!(status.transitions.last().unwrap().reason, "step 19");
+    }
+
+    #[test]
+    fn probation_promotes_after_successes() {
+        let settings = LifecycleSettings {
+            probation_requests: 3,
+            ..Default::default()
+        };
+        let mut state = probation(&settings);
+        assert_eq!(state.state(), Lifecycle::Probation);
+        assert!(!state.record(false, &settings));
+        assert!(!state.record(false, &settings));
+        // a failure starts over
+        assert!(!state.record(true, &settings));
+        assert!(!state.record(false, &settings));
+        assert!(!state.record(false, &settings));
+        assert!(state.record(false, &settings));
+        assert_eq!(state.state(), Lifecycle::Active);
+        assert!(!state.record(false, &settings));
+
+        let direct = LifecycleSettings {
+            probation_requests: 0,
+            ..Default::default()
+        };
+        assert_eq!(probation(&direct).state(), Lifecycle::Active);
+    }
+
+    #[test]
+    fn drain_expiry() {
+        let mut state = LifecycleState::default();
+        let immediate = LifecycleSettings {
+            drain_timeout: Duration::from_nanos(1),
+            ..Default::default()
+        };
+        assert!(!state.drain_expired(&immediate));
+        state.transition(Lifecycle::Draining, "drain".to_string());
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(state.drain_expired(&immediate));
+        let unbounded = LifecycleSettings {
+            drain_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(!state.drain_expired(&unbounded));
+    }
+
+    #[test]
+    fn probation_weight() {
+        let settings = LifecycleSettings::default();
+        let state = probation(&settings);
+        assert_eq!(state.effective_weight(50, &settings), 5);
+        assert_eq!(state.effective_weight(3, &settings), 1);
+        assert_eq!(state.effective_weight(0, &settings), 0);
+        assert_eq!(
+            LifecycleState::default().effective_weight(50, &settings),
+            50
+        );
+        // would overflow in u32
+        assert_eq!(state.effective_weight(u32::MAX, &settings), u32::MAX / 10);
+        let boosted = LifecycleSettings {
+            probation_weight_percent: 1000,
+            ..Default::default()
+        };
+        assert_eq!(state.effective_weight(u32::MAX, &boosted), u32::MAX);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::time::{civil_from_days, unix_now};
+use rand::Rng;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// leveled logging, one json object per line on stdout (stderr for errors and warnings)
+//
+// lines logged while a request is handled carry its `request_id`, see `with_request_id`
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+pub const LEVEL_NAMES: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            other => Err(format!("unknown log level '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(LEVEL_NAMES[*self as usize - 1])
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+// takes effect for every line logged afterwards, on every task
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// 16 hex digits for requests that come without an `X-Request-Id`
+pub fn generate_request_id() -> String {
+    format!("{:016x}", rand::rng().random::<u64>())
+}
+
+// the client's `X-Request-Id` if it is reasonable to log and forward, else `None`
+pub fn valid_request_id(id: &str) -> Option<&str> {
+    let id = id.trim();
+    (!id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())).then_some(id)
+}
+
+// runs `future` with every line it logs tagged with `request_id`
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+// `2024-05-01T12:34:56.789Z`
+fn timestamp() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_millis())
+        .unwrap_or(0);
+    let secs = unix_now();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        millis
+    )
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    ts: String,
+    level: Level,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    msg: String,
+}
+
+pub fn emit(level: Level, target: &str, message: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+    let line = Line {
+        ts: timestamp(),
+        level,
+        target,
+        request_id: REQUEST_ID.try_with(|id| id.clone()).ok(),
+        msg: message.to_string(),
+    };
+    let Ok(json) = serde_json::to_string(&line) else {
+        return;
+    };
+    if level <= Level::Warn {
+        eprintln!("{}", json);
+    } else {
+        println!("{}", json);
+    }
+}
+
+// declared with `#[macro_use]` ahead of the other modules so these are in scope everywhere,
+// a `use` can't import `warn` since it clashes with the builtin attribute
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Error, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Warn, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Info, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Debug, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Trace, module_path!(), format_args!($($arg)*))
+    };
+}
@@ -1,9 +1,51 @@
-use rand::Rng;
+#[macro_use]
+mod log;
+
+mod affinity;
+mod auth;
+mod breaker;
+mod config;
+mod health;
+mod http;
+mod latency;
+mod lifecycle;
+mod metrics;
+mod models;
+mod outlier;
+mod pool;
+mod proxy;
+mod queue;
+mod ratelimit;
+mod routes;
+mod strategy;
+mod time;
+mod timeouts;
+mod usage;
+
+use affinity::{HashRing, PrefixSettings, PrefixTable};
+use auth::KeyStore;
+use breaker::{Breaker, BreakerSettings, BreakerStatus, CircuitState};
+use config::Config;
+use health::{HealthSettings, HealthState};
+use latency::{LatencySettings, LatencyState, LatencyStatus};
+use lifecycle::{Lifecycle, LifecycleSettings, LifecycleState, LifecycleStatus};
+use metrics::{Metrics, ServiceGauges};
+use models::{ModelCache, ModelsSettings};
+use outlier::{OutlierSettings, OutlierState, OutlierStatus};
+use pool::{ConnectionPool, PoolSettings};
+use queue::{QueueRejection, QueueSettings, WaitQueue};
+use ratelimit::{RateLimiter, TenantLimits};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{env, sync::Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use strategy::{BalancingStrategy, Candidate};
+use timeouts::{RequestTimeouts, TimeoutKind};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
+use usage::{UsageFilter, UsageLedger};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Service {
@@ -11,6 +53,47 @@ struct Service {
     weight: u32,
     ip: String,
     port: u16,
+    // models served by this backend - empty means it accepts any model
+    #[serde(default)]
+    models: Vec<String>,
+    // requests the backend runs at once before new ones have to wait - unset means no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_concurrency: Option<u32>,
+    // route pools (`chat`, `embeddings`, ...) the backend serves, see `routes::ROUTES` -
+    // empty means it serves any pool without members of its own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pools: Vec<String>,
+    // headers set on every request forwarded to the backend, such as its own api key -
+    // only the names are shown when services are listed
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "redact_headers"
+    )]
+    headers: BTreeMap<String, String>,
+}
+
+fn redact_headers<S: serde::Serializer>(
+    headers: &BTreeMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(headers.keys().map(|name| (name, "<redacted>")))
+}
+
+impl Service {
+    fn serves_model(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|m| m == model)
+    }
+
+    fn in_pool(&self, pool: &str) -> bool {
+        self.pools.iter().any(|p| p == pool)
+    }
+
+    fn concurrency_limit(&self) -> Option<usize> {
+        self.max_concurrency
+            .filter(|limit| *limit > 0)
+            .map(|limit| limit as usize)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -19,31 +102,256 @@ struct RegisterRequest {
     weight: u32,
     ip: String,
     port: u16,
+    #[serde(default)]
+    models: Vec<String>,
+    #[serde(default)]
+    max_concurrency: Option<u32>,
+    #[serde(default)]
+    pools: Vec<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+}
+
+// runtime state kept alongside each registered service
+struct ServiceState {
+    pool: ConnectionPool,
+    health: Mutex<HealthState>,
+    outlier: Mutex<OutlierState>,
+    // requests currently being forwarded to the service
+    in_flight: AtomicUsize,
+    latency: Mutex<LatencyState>,
+    breaker: Mutex<Breaker>,
+    // what the service answered to `GET /v1/models` last
+    models: ModelCache,
+    lifecycle: Mutex<LifecycleState>,
+}
+
+impl ServiceState {
+    fn new(pool_settings: PoolSettings) -> Self {
+        Self {
+            pool: ConnectionPool::new(pool_settings),
+            health: Mutex::new(HealthState::default()),
+            outlier: Mutex::new(OutlierState::default()),
+            in_flight: AtomicUsize::new(0),
+            latency: Mutex::new(LatencyState::default()),
+            breaker: Mutex::new(Breaker::default()),
+            models: ModelCache::default(),
+            lifecycle: Mutex::new(LifecycleState::default()),
+        }
+    }
+
+    // whether new requests may be sent to this service
+    fn is_eligible(&self, breaker: &BreakerSettings) -> bool {
+        self.lifecycle().accepts_requests()
+            && self.health.lock().unwrap().healthy
+            && !self.outlier.lock().unwrap().is_ejected()
+            && self.breaker.lock().unwrap().allows_requests(breaker)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    fn lifecycle(&self) -> Lifecycle {
+        self.lifecycle.lock().unwrap().state()
+    }
+
+    // takes one of the service's request slots unless it already runs `limit` requests
+    fn try_reserve(&self, limit: Option<usize>) -> bool {
+        self.in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| match limit {
+                Some(limit) if n >= limit => None,
+                _ => Some(n + 1),
+            })
+            .is_ok()
+    }
+}
+
+// counts a request as in flight for as long as the guard lives, see
+// `ServiceRegistry::reserve`
+struct InFlightGuard {
+    state: Arc<ServiceState>,
+    // woken when the slot is given back
+    queue: Arc<WaitQueue>,
+    // woken when a draining service finished its last request
+    drained: Arc<Notify>,
 }
 
-#[derive(Debug, Clone)]
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let previous = self.state.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.queue.notify();
+        if previous == 1 && self.state.lifecycle() == Lifecycle::Draining {
+            self.drained.notify_one();
+        }
+    }
+}
+
+// a service as listed by `GET /api/services`, with its runtime state
+#[derive(Serialize)]
+struct ServiceStatus {
+    #[serde(flatten)]
+    service: Service,
+    lifecycle: LifecycleStatus,
+    health: HealthState,
+    outlier: OutlierStatus,
+    in_flight: usize,
+    latency: Option<LatencyStatus>,
+    circuit: BreakerStatus,
+}
+
+// an api key as listed by `GET /api/keys`
+#[derive(Serialize)]
+struct KeyStatus {
+    #[serde(flatten)]
+    key: auth::KeyInfo,
+    limits: TenantLimits,
+    // tokens used so far this calendar month, counted against `limits.monthly_tokens`
+    month_tokens: u64,
+}
+
+// `GET /api/usage` filters: `since`/`until` as unix seconds or `window` reaching back
+// from now (`30m`, `24h`, `7d` or `month` for the current calendar month), plus `key`
+// and `service` - `None` if one of them doesn't parse
+fn usage_filter(query: &str) -> Option<UsageFilter> {
+    let param = |name| http::query_param(query, name).filter(|v| !v.is_empty());
+    let mut filter = UsageFilter {
+        since: param("since").map(str::parse).transpose().ok()?,
+        until: param("until").map(str::parse).transpose().ok()?,
+        key: param("key").map(str::to_string),
+        service: param("service").map(str::to_string),
+    };
+    if let Some(window) = param("window") {
+        let now = time::unix_now();
+        let since = if window == "month" {
+            usage::month_start(now)
+        } else {
+            let split = window.len().checked_sub(1)?;
+            let amount: u64 = window.get(..split)?.parse().ok()?;
+            let unit = match &window[split..] {
+                "m" => 60,
+                "h" => 3600,
+                "d" => 86_400,
+                _ => return None,
+            };
+            now.saturating_sub(amount * unit)
+        };
+        filter.since = Some(since);
+    }
+    Some(filter)
+}
+
+#[derive(Clone)]
 struct ServiceRegistry {
     services: Arc<RwLock<Vec<Service>>>,
+    states: Arc<RwLock<HashMap<String, Arc<ServiceState>>>>,
+    pool_settings: PoolSettings,
+    lifecycle_settings: LifecycleSettings,
+    // woken when a draining service may be ready for removal, see `remove_drained`
+    drained: Arc<Notify>,
+    // services drained through `POST /api/services/{name}/drain` - they are disabled rather
+    // than removed once their drain is done, so the watcher's next sync can't bring them
+    // back active
+    drain_requested: Arc<Mutex<HashSet<String>>>,
+    // swappable at runtime through `PUT /api/strategy`
+    strategy: Arc<std::sync::RwLock<Arc<dyn BalancingStrategy>>>,
+    // rebuilt whenever services join, leave or change weight
+    ring: Arc<std::sync::RwLock<HashRing>>,
+    prefixes: Arc<PrefixTable>,
+    queue: Arc<WaitQueue>,
+    keys: Arc<KeyStore>,
+    limiter: Arc<RateLimiter>,
+    usage: Arc<UsageLedger>,
+    metrics: Arc<Metrics>,
 }
 
 impl ServiceRegistry {
-    fn new() -> Self {
+    fn new(
+        pool_settings: PoolSettings,
+        lifecycle_settings: LifecycleSettings,
+        strategy: Arc<dyn BalancingStrategy>,
+        rate_limit: TenantLimits,
+    ) -> Self {
         Self {
             services: Arc::new(RwLock::new(Vec::new())),
+            states: Arc::new(RwLock::new(HashMap::new())),
+            pool_settings,
+            lifecycle_settings,
+            drained: Arc::new(Notify::new()),
+            drain_requested: Arc::new(Mutex::new(HashSet::new())),
+            strategy: Arc::new(std::sync::RwLock::new(strategy)),
+            ring: Arc::new(std::sync::RwLock::new(HashRing::default())),
+            prefixes: Arc::new(PrefixTable::default()),
+            queue: Arc::new(WaitQueue::default()),
+            keys: Arc::new(KeyStore::default()),
+            limiter: Arc::new(RateLimiter::new(rate_limit)),
+            usage: Arc::new(UsageLedger::default()),
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
+    fn strategy(&self) -> Arc<dyn BalancingStrategy> {
+        self.strategy.read().unwrap().clone()
+    }
+
+    fn set_strategy(&self, strategy: Arc<dyn BalancingStrategy>) {
+        info!(
+            "switching balancing strategy: {} -> {}",
+            self.strategy().name(),
+            strategy.name()
+        );
+        *self.strategy.write().unwrap() = strategy;
+    }
+
+    // picks one of `services` with the active balancing strategy
+    async fn select_service(&self, services: &[Service]) -> Option<Service> {
+        if services.is_empty() {
+            debug!("no services available for selection");
+            return None;
+        }
+
+        let states = self.states.read().await;
+        let candidates: Vec<Candidate> = services
+            .iter()
+            .map(|service| {
+                let state = states.get(&service.name);
+                Candidate {
+                    service,
+                    in_flight: state.map(|s| s.in_flight()).unwrap_or(0),
+                    latency: state.and_then(|s| s.latency.lock().unwrap().status()),
+                }
+            })
+            .collect();
+
+        let chosen = self.strategy().select(&candidates);
+        Some(services[chosen].clone())
+    }
+
     async fn register_service(&self, service: Service) {
-        println!(
+        debug!(
             "registering service: {} (weight: {}) at {}:{}",
             service.name, service.weight, service.ip, service.port
         );
 
         // acquire write lock (blocks other writers, allows concurrent readers)
         let mut services = self.services.write().await;
+        let mut states = self.states.write().await;
 
         if let Some(existing) = services.iter_mut().find(|s| s.name == service.name) {
-            println!(
+            // the service may have been redeployed with other models
+            if let Some(state) = states.get(&service.name) {
+                state.models.clear();
+            }
+            // pooled connections, probe results and latencies belong to the old address
+            if (existing.ip != service.ip || existing.port != service.port)
+                && let Some(state) = states.get(&service.name)
+            {
+                state.pool.clear();
+                *state.health.lock().unwrap() = HealthState::default();
+                *state.latency.lock().unwrap() = LatencyState::default();
+                *state.breaker.lock().unwrap() = Breaker::default();
+            }
+            info!(
                 "updating existing service '{}': weight {} -> {}, address {}:{} -> {}:{}",
                 service.name,
                 existing.weight,
@@ -55,45 +363,37 @@ impl ServiceRegistry {
             );
             *existing = service;
         } else {
-            println!(
-                "registered new service: {} (weight: {}) at {}:{}",
-                service.name, service.weight, service.ip, service.port
+            info!(
+                "registered new service: {} (weight: {}, models: {:?}) at {}:{}",
+                service.name, service.weight, service.models, service.ip, service.port
             );
+            let state = ServiceState::new(self.pool_settings.clone());
+            states.insert(service.name.clone(), Arc::new(state));
             services.push(service);
         }
+        // a new or changed service may take requests that are waiting for a slot
+        self.queue.notify();
+        self.metrics.record_registration();
+
+        *self.ring.write().unwrap() = HashRing::build(&services);
 
-        println!("total services registered: {}", services.len());
+        debug!("total services registered: {}", services.len());
         for service in services.iter() {
-            println!(
+            trace!(
                 "  - {} (weight: {}) at {}:{}",
                 service.name, service.weight, service.ip, service.port
             );
         }
     }
 
-    async fn unregister_service(&self, name: &str) -> bool {
-        // acquire write lock (blocks other writers, allows concurrent readers)
-        let mut services = self.services.write().await;
-
-        let initial_len = services.len();
-        services.retain(|s| s.name != name);
-
-        let removed = services.len() < initial_len;
-        if removed {
-            println!("unregistered service: {}", name);
+    // remembers an operator drain until it is done or another lifecycle action supersedes it
+    fn note_drain_request(&self, name: &str, drained: bool) {
+        let mut requested = self.drain_requested.lock().unwrap();
+        if drained {
+            requested.insert(name.to_string());
         } else {
-            println!("service not found for unregistration: {}", name);
-        }
-
-        println!("total services registered: {}", services.len());
-        for service in services.iter() {
-            println!(
-                "  - {} (weight: {}) at {}:{}",
-                service.name, service.weight, service.ip, service.port
-            );
+            requested.remove(name);
         }
-
-        removed
     }
 
     async fn list_services(&self) -> Vec<Service> {
@@ -101,127 +401,561 @@ impl ServiceRegistry {
         services.clone()
     }
 
-    // reminiscence of previous environment-variable-address-approach :D
-    async fn get_service_address(&self, service_name: &str) -> Option<String> {
+    // services of a route pool, or the services without pools when it has no members
+    async fn pool_services(&self, pool: &str) -> Vec<Service> {
         let services = self.services.read().await;
-        if let Some(service) = services.iter().find(|s| s.name == service_name) {
-            let address = format!("{}:{}", service.ip, service.port);
-            println!(
-                "resolved service '{}' to address: {}",
-                service_name, address
-            );
-            Some(address)
-        } else {
-            println!("service '{}' not found in registry", service_name);
-            None
+        let members: Vec<Service> = services
+            .iter()
+            .filter(|s| s.in_pool(pool))
+            .cloned()
+            .collect();
+        if !members.is_empty() {
+            return members;
         }
+        services
+            .iter()
+            .filter(|s| s.pools.is_empty())
+            .cloned()
+            .collect()
     }
-}
 
-fn select_service(services: &[Service]) -> Option<&Service> {
-    if services.is_empty() {
-        println!("no services available for selection");
-        return None;
+    // services together with their runtime state, for the api listing
+    async fn describe_services(&self) -> Vec<ServiceStatus> {
+        let services = self.services.read().await;
+        let states = self.states.read().await;
+        services
+            .iter()
+            .filter_map(|service| {
+                let state = states.get(&service.name)?;
+                Some(ServiceStatus {
+                    service: service.clone(),
+                    lifecycle: state.lifecycle.lock().unwrap().status(),
+                    health: state.health.lock().unwrap().clone(),
+                    outlier: state.outlier.lock().unwrap().status(),
+                    in_flight: state.in_flight(),
+                    latency: state.latency.lock().unwrap().status(),
+                    circuit: state.breaker.lock().unwrap().status(),
+                })
+            })
+            .collect()
     }
 
-    let total_weight: u32 = services.iter().map(|s| s.weight).sum();
-    if total_weight == 0 {
-        println!(
-            "all services have zero weight, selecting first service: {}",
-            services[0].name
-        );
-        return services.first();
+    // drops services that should not receive new requests right now, services on
+    // probation are kept with their reduced weight
+    async fn eligible_services(
+        &self,
+        services: Vec<Service>,
+        breaker: &BreakerSettings,
+    ) -> Vec<Service> {
+        let states = self.states.read().await;
+        services
+            .into_iter()
+            .filter_map(|mut service| {
+                let state = states.get(&service.name)?;
+                if !state.is_eligible(breaker) {
+                    return None;
+                }
+                service.weight = state
+                    .lifecycle
+                    .lock()
+                    .unwrap()
+                    .effective_weight(service.weight, &self.lifecycle_settings);
+                Some(service)
+            })
+            .collect()
+    }
+
+    // services among `services` that are below their concurrency limit
+    async fn with_capacity(&self, services: &[Service]) -> Vec<Service> {
+        let states = self.states.read().await;
+        services
+            .iter()
+            .filter(|s| {
+                let in_flight = states.get(&s.name).map(|state| state.in_flight());
+                match (in_flight, s.concurrency_limit()) {
+                    (Some(in_flight), Some(limit)) => in_flight < limit,
+                    (in_flight, _) => in_flight.is_some(),
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    // the services among `services` that can take a request now, waiting in the
+    // bounded queue while all of them are at their concurrency limit
+    async fn wait_for_capacity(
+        &self,
+        services: &[Service],
+        settings: &QueueSettings,
+    ) -> Result<Vec<Service>, QueueRejection> {
+        let deadline = tokio::time::Instant::now() + settings.timeout;
+        let mut ticket = None;
+        loop {
+            let released = self.queue.released();
+            let available = self.with_capacity(services).await;
+            if !available.is_empty() || services.is_empty() {
+                return Ok(available);
+            }
+
+            if ticket.is_none() {
+                ticket = Some(self.queue.join(settings).ok_or(QueueRejection::Full)?);
+                info!(
+                    "all {} candidate services are at their concurrency limit, queued ({} waiting)",
+                    services.len(),
+                    self.queue.waiting()
+                );
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(QueueRejection::TimedOut);
+            }
+        }
     }
 
-    let mut rng = rand::rng();
-    let mut choice = rng.random_range(0..total_weight);
-    let original_choice = choice;
+    // takes a request slot on the service, `None` if it is at its concurrency limit
+    async fn reserve(&self, service: &Service) -> Option<InFlightGuard> {
+        let state = self.service_state(&service.name).await?;
+        if !state.try_reserve(service.concurrency_limit()) {
+            return None;
+        }
+        Some(InFlightGuard {
+            state,
+            queue: self.queue.clone(),
+            drained: self.drained.clone(),
+        })
+    }
 
-    for service in services {
-        if choice < service.weight {
-            println!(
-                "selected service '{}' (choice: {}/{}, weight: {})",
-                service.name, original_choice, total_weight, service.weight
+    // claims a request slot from the service's circuit breaker, false while it is
+    // open or all half-open trial slots are taken, or the service stopped taking
+    // requests since it was picked
+    async fn admit(&self, name: &str, settings: &BreakerSettings) -> bool {
+        let states = self.states.read().await;
+        let Some(state) = states.get(name) else {
+            return false;
+        };
+        if !state.lifecycle().accepts_requests() {
+            return false;
+        }
+        let mut breaker = state.breaker.lock().unwrap();
+        let was_open = breaker.status().state == CircuitState::Open;
+        let admitted = breaker.admit(settings);
+        if was_open && admitted {
+            info!(
+                "circuit for service '{}' half-open, sending trial request",
+                name
             );
-            return Some(service);
         }
-        // choice -= service.weight; // improved to :
-        choice = choice.saturating_sub(service.weight);
+        admitted
     }
 
-    // fallback to first service (should be rare)
-    println!(
-        "A rare thing has happened and none of the services got selected\nLet's fallback to the first service: {}",
-        services[0].name
-    );
-    services.first()
-}
+    // feeds the result of a proxied request into the service's circuit breaker
+    async fn record_circuit(&self, name: &str, failure: Option<&str>, settings: &BreakerSettings) {
+        let states = self.states.read().await;
+        let Some(state) = states.get(name) else {
+            return;
+        };
+        let mut breaker = state.breaker.lock().unwrap();
+        match breaker.record(failure, settings) {
+            Some(CircuitState::Open) => warn!(
+                "circuit for service '{}' opened for {}s: {}",
+                name,
+                settings.open_duration.as_secs(),
+                breaker
+                    .status()
+                    .transitions
+                    .last()
+                    .map(|t| t.reason.as_str())
+                    .unwrap_or("")
+            ),
+            Some(CircuitState::Closed) => {
+                info!("circuit for service '{}' closed again", name)
+            }
+            _ => {}
+        }
+    }
 
-async fn read_request(
-    stream: &mut TcpStream,
-    peer_addr: std::net::SocketAddr,
-) -> Result<(String, Vec<u8>), Box<dyn std::error::Error>> {
-    let mut buffer = Vec::new();
-    let mut temp_buf = [0; 1024];
+    // the service a session key is pinned to on the hash ring, among `services`
+    fn sticky_service(&self, key: &str, services: &[Service]) -> Option<Service> {
+        let ring = self.ring.read().unwrap();
+        let name = ring.lookup(key, |name| services.iter().any(|s| s.name == name))?;
+        services.iter().find(|s| s.name == name).cloned()
+    }
 
-    loop {
-        let bytes_read = stream.read(&mut temp_buf).await?;
-        // sanity check
-        if bytes_read == 0 {
-            println!(
-                "client {} closed the connection gracefully - zero bytes read : EOF",
-                peer_addr
+    // the service that most recently served a prompt prefix, among `services`,
+    // unless it is already busier than its weighted share of the load allows
+    async fn prefix_service(
+        &self,
+        key: u64,
+        services: &[Service],
+        settings: &PrefixSettings,
+    ) -> Option<Service> {
+        let name = self.prefixes.lookup(key, settings)?;
+        let service = services.iter().find(|s| s.name == name)?;
+
+        let states = self.states.read().await;
+        let in_flight = |name: &str| states.get(name).map(|s| s.in_flight()).unwrap_or(0);
+        let total_in_flight: usize = services.iter().map(|s| in_flight(&s.name)).sum();
+        // u64, the weights of a few services can add up past u32::MAX
+        let total_weight: u64 = services.iter().map(|s| u64::from(s.weight)).sum();
+        let share = if total_weight == 0 {
+            1.0 / services.len() as f64
+        } else {
+            service.weight as f64 / total_weight as f64
+        };
+
+        let current = in_flight(&service.name);
+        if !affinity::within_bounded_load(current, share, total_in_flight, settings.load_factor) {
+            debug!(
+                "prefix {:016x} last served by '{}' but it is too busy ({} of {} in flight)",
+                key, service.name, current, total_in_flight
             );
-            break;
+            return None;
         }
+        Some(service.clone())
+    }
 
-        buffer.extend_from_slice(&temp_buf[..bytes_read]);
+    // feeds the timings of a relayed response into the service's latency averages
+    async fn record_latency(
+        &self,
+        name: &str,
+        stats: &proxy::RelayStats,
+        settings: &LatencySettings,
+    ) {
+        let states = self.states.read().await;
+        if let Some(state) = states.get(name) {
+            // a response cut short by the client says nothing about its full duration
+            let total = (!stats.client_closed).then_some(stats.total);
+            state
+                .latency
+                .lock()
+                .unwrap()
+                .record(stats.time_to_first_byte, total, settings);
+        }
+    }
 
-        // break loop - if found end of header
-        if buffer.windows(4).any(|window| window == b"\r\n\r\n") {
-            break;
+    // feeds the result of a proxied request into passive outlier detection,
+    // `failure` is `None` for a request the service handled fine
+    async fn record_outcome(
+        &self,
+        name: &str,
+        failure: Option<String>,
+        settings: &OutlierSettings,
+    ) {
+        let states = self.states.read().await;
+        let Some(state) = states.get(name) else {
+            return;
+        };
+
+        let Some(error) = failure else {
+            state.outlier.lock().unwrap().record_success(settings);
+            return;
+        };
+
+        // cap the share of ejected services so a bad rollout can't empty the pool
+        let ejected = states
+            .values()
+            .filter(|s| s.outlier.lock().unwrap().is_ejected())
+            .count();
+        let can_eject =
+            (ejected + 1) * 100 <= states.len() * settings.max_ejection_percent as usize;
+
+        let mut outlier = state.outlier.lock().unwrap();
+        match outlier.record_failure(error.clone(), settings, can_eject) {
+            Some(duration) => warn!(
+                "service '{}' ejected for {}s after consecutive failures, last: {}",
+                name,
+                duration.as_secs(),
+                error
+            ),
+            None if !can_eject && !outlier.is_ejected() => warn!(
+                "service '{}' is failing ({}) but {} of {} services are already ejected",
+                name,
+                error,
+                ejected,
+                states.len()
+            ),
+            None => {}
         }
     }
 
-    // separate headers and body from http request
-    let request_str = String::from_utf8_lossy(&buffer);
-    let (headers, _) = request_str
-        .split_once("\r\n\r\n")
-        .unwrap_or((&request_str, ""));
+    // probes every registered service once and updates its health
+    async fn run_health_checks(&self, settings: &HealthSettings) {
+        let targets: Vec<(String, String, BTreeMap<String, String>)> = self
+            .list_services()
+            .await
+            .into_iter()
+            .map(|s| (s.name, format!("{}:{}", s.ip, s.port), s.headers))
+            .collect();
 
-    let body_start = headers.len() + 4;
-    let body = if body_start < buffer.len() {
-        buffer[body_start..].to_vec()
-    } else {
-        Vec::new()
-    };
+        let probes = targets
+            .iter()
+            .map(|(_, address, headers)| health::probe(address, headers, settings));
+        let results = futures::future::join_all(probes).await;
 
-    println!(
-        "read request from {}: headers {} bytes, body {} bytes",
-        peer_addr,
-        headers.len(),
-        body.len()
-    );
-    Ok((headers.to_string(), body))
+        let states = self.states.read().await;
+        for ((name, address, _), result) in targets.into_iter().zip(results) {
+            // the service may have been unregistered while probing
+            let Some(state) = states.get(&name) else {
+                continue;
+            };
+            let error = result.error.clone().unwrap_or_default();
+            let mut health = state.health.lock().unwrap();
+            match health.record(result, settings) {
+                Some(false) => warn!(
+                    "service '{}' at {} marked unhealthy after {} failed probes: {}",
+                    name, address, health.consecutive_failures, error
+                ),
+                Some(true) => info!(
+                    "service '{}' at {} marked healthy again after {} successful probes",
+                    name, address, health.consecutive_successes
+                ),
+                // failures of an already ejected service are not repeated every round
+                None if health.healthy && health.consecutive_failures > 0 => debug!(
+                    "health probe for '{}' at {}: {} (failures: {}, successes: {})",
+                    name,
+                    address,
+                    if error.is_empty() { "ok" } else { &error },
+                    health.consecutive_failures,
+                    health.consecutive_successes
+                ),
+                None => {}
+            }
+        }
+    }
+
+    // the models of every eligible service, asked for in parallel and cached for
+    // `cache_ttl` - a service that doesn't answer contributes its last known list,
+    // or else the models it registered with
+    async fn list_models(
+        &self,
+        settings: &ModelsSettings,
+        breaker: &BreakerSettings,
+    ) -> serde_json::Value {
+        let services = self
+            .eligible_services(self.list_services().await, breaker)
+            .await;
+        let lookups = services.into_iter().map(|service| async move {
+            let state = self.service_state(&service.name).await?;
+            if let Some(models) = state.models.fresh(settings.cache_ttl) {
+                return Some((service.name, models));
+            }
+            let address = format!("{}:{}", service.ip, service.port);
+            let fetched =
+                tokio::time::timeout(settings.timeout, models::fetch(&address, &service.headers))
+                    .await
+                    .map_err(|_| format!("timed out after {}ms", settings.timeout.as_millis()))
+                    .and_then(|result| result.map_err(|e| e.to_string()));
+            let models = match fetched {
+                Ok(models) => {
+                    state.models.store(models.clone());
+                    models
+                }
+                Err(e) => {
+                    warn!(
+                        "fetching models from '{}' at {} failed: {}",
+                        service.name, address, e
+                    );
+                    state
+                        .models
+                        .stale()
+                        .unwrap_or_else(|| models::declared(&service.models))
+                }
+            };
+            // requests only reach the service for the models it registered with
+            let models = models
+                .into_iter()
+                .filter(|model| {
+                    model
+                        .get("id")
+                        .and_then(|id| id.as_str())
+                        .is_some_and(|id| service.serves_model(id))
+                })
+                .collect();
+            Some((service.name, models))
+        });
+        let lists = futures::future::join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        models::merge(lists)
+    }
+
+    // `GET /metrics`, with the gauges read from the current service states
+    async fn render_metrics(&self) -> String {
+        let services = self.services.read().await;
+        let states = self.states.read().await;
+        let gauges: Vec<ServiceGauges> = services
+            .iter()
+            .filter_map(|service| {
+                let state = states.get(&service.name)?;
+                Some(ServiceGauges {
+                    name: service.name.clone(),
+                    in_flight: state.in_flight(),
+                    healthy: state.health.lock().unwrap().healthy,
+                    ejected: state.outlier.lock().unwrap().is_ejected(),
+                    circuit_open: state.breaker.lock().unwrap().status().state
+                        == CircuitState::Open,
+                    lifecycle: state.lifecycle(),
+                })
+            })
+            .collect();
+        self.metrics.render(&gauges, self.queue.waiting())
+    }
+
+    async fn service_state(&self, name: &str) -> Option<Arc<ServiceState>> {
+        self.states.read().await.get(name).cloned()
+    }
+
+    async fn sweep_pools(&self) {
+        let states = self.states.read().await;
+        for (name, state) in states.iter() {
+            let dropped = state.pool.sweep();
+            if dropped > 0 {
+                debug!(
+                    "closed {} stale pooled connections to '{}', {} idle left",
+                    dropped,
+                    name,
+                    state.pool.idle_count()
+                );
+            }
+        }
+    }
+
+    // moves a service to another lifecycle state, `None` if there is no such service -
+    // a draining service without requests in flight is done right away, see `remove_drained`
+    async fn set_lifecycle(&self, name: &str, to: Lifecycle, reason: String) -> Option<Lifecycle> {
+        let state = self.service_state(name).await?;
+        let changed = state
+            .lifecycle
+            .lock()
+            .unwrap()
+            .transition(to, reason.clone());
+        match changed {
+            Some(from) => info!(
+                "service '{}' {} -> {} ({}, {} in flight)",
+                name,
+                from.name(),
+                to.name(),
+                reason,
+                state.in_flight()
+            ),
+            None => debug!("service '{}' already {}", name, to.name()),
+        }
+        if to == Lifecycle::Draining {
+            self.remove_drained().await;
+        } else if to.accepts_requests() {
+            // requests waiting for a slot may go to the service now
+            self.queue.notify();
+        }
+        Some(to)
+    }
+
+    // feeds the result of a proxied request into the service's probation
+    async fn record_probation(&self, name: &str, failed: bool) {
+        let Some(state) = self.service_state(name).await else {
+            return;
+        };
+        let mut lifecycle = state.lifecycle.lock().unwrap();
+        if lifecycle.record(failed, &self.lifecycle_settings) {
+            info!("service '{}' passed probation, active again", name);
+        }
+    }
+
+    // removes draining services whose requests are done, or that ran out of time for them -
+    // those drained by an operator stay registered but disabled
+    async fn remove_drained(&self) -> usize {
+        let mut services = self.services.write().await;
+        let mut states = self.states.write().await;
+        let drained: Vec<String> = states
+            .iter()
+            .filter(|(_, state)| {
+                let lifecycle = state.lifecycle.lock().unwrap();
+                lifecycle.state() == Lifecycle::Draining
+                    && (state.in_flight() == 0 || lifecycle.drain_expired(&self.lifecycle_settings))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        let (disabled, drained): (Vec<String>, Vec<String>) = drained
+            .into_iter()
+            .partition(|name| self.drain_requested.lock().unwrap().remove(name));
+        for name in &disabled {
+            let state = &states[name];
+            state
+                .lifecycle
+                .lock()
+                .unwrap()
+                .transition(Lifecycle::Disabled, "drained".to_string());
+            info!(
+                "service '{}' drained with {} requests in flight, disabled until it is enabled",
+                name,
+                state.in_flight()
+            );
+        }
+        if drained.is_empty() {
+            return 0;
+        }
+
+        for name in &drained {
+            let in_flight = states.remove(name).map_or(0, |state| state.in_flight());
+            services.retain(|s| &s.name != name);
+            self.metrics.record_unregistration();
+            if in_flight == 0 {
+                info!("removed drained service: {}", name);
+            } else {
+                warn!(
+                    "removed draining service '{}' after {}s with {} requests still in flight",
+                    name,
+                    self.lifecycle_settings.drain_timeout.as_secs(),
+                    in_flight
+                );
+            }
+        }
+        *self.ring.write().unwrap() = HashRing::build(&services);
+        debug!("total services registered: {}", services.len());
+        drained.len()
+    }
+
+    // reminiscence of previous environment-variable-address-approach :D
+    async fn get_service_address(&self, service_name: &str) -> Option<String> {
+        let services = self.services.read().await;
+        if let Some(service) = services.iter().find(|s| s.name == service_name) {
+            let address = format!("{}:{}", service.ip, service.port);
+            debug!(
+                "resolved service '{}' to address: {}",
+                service_name, address
+            );
+            Some(address)
+        } else {
+            warn!("service '{}' not found in registry", service_name);
+            None
+        }
+    }
 }
 
 async fn handle_api_request(
-    mut stream: TcpStream,
-    registry: Arc<ServiceRegistry>,
+    stream: &mut TcpStream,
+    registry: &ServiceRegistry,
     method: &str,
     path: &str,
     body: &[u8],
     peer_addr: std::net::SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!(
+    debug!(
         "handling api request from {}: {} {}",
         peer_addr, method, path
     );
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
 
     match (method, path) {
+        ("GET", "/metrics") => {
+            let metrics = registry.render_metrics().await;
+            stream
+                .write_all(http::text_response("200 OK", &metrics).as_bytes())
+                .await?;
+        }
         ("POST", "/api/register") => {
             if let Ok(req) = serde_json::from_slice::<RegisterRequest>(body) {
-                println!(
+                info!(
                     "registration request from {}: {} (weight: {}) at {}:{}",
                     peer_addr, req.name, req.weight, req.ip, req.port
                 );
@@ -230,54 +964,341 @@ async fn handle_api_request(
                     weight: req.weight,
                     ip: req.ip,
                     port: req.port,
+                    models: req.models,
+                    max_concurrency: req.max_concurrency,
+                    pools: req.pools,
+                    headers: req.headers,
                 };
                 registry.register_service(service).await;
                 stream
-                    .write_all(b"HTTP/1.1 200 OK\r\n\r\nRegistered")
+                    .write_all(http::text_response("200 OK", "Registered").as_bytes())
                     .await?;
             } else {
-                println!("invalid json in registration request from {}", peer_addr);
+                warn!("invalid json in registration request from {}", peer_addr);
                 stream
-                    .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\nInvalid JSON")
+                    .write_all(http::text_response("400 Bad Request", "Invalid JSON").as_bytes())
                     .await?;
             }
         }
         ("DELETE", path) if path.starts_with("/api/unregister/") => {
             let service_name = path.strip_prefix("/api/unregister/").unwrap_or("");
-            println!(
+            info!(
                 "unregistration request from {} for service: {}",
                 peer_addr, service_name
             );
-            if registry.unregister_service(service_name).await {
-                stream
-                    .write_all(b"HTTP/1.1 200 OK\r\n\r\nUnregistered")
-                    .await?;
-            } else {
-                stream
-                    .write_all(b"HTTP/1.1 404 Not Found\r\n\r\nService not found")
-                    .await?;
-            }
+            // drained rather than dropped, so requests in flight on it can finish - and
+            // removed afterwards even if an operator drained it before
+            registry.note_drain_request(service_name, false);
+            let reason = format!("unregistered by {}", peer_addr.ip());
+            let response = match registry
+                .set_lifecycle(service_name, Lifecycle::Draining, reason)
+                .await
+            {
+                None => http::text_response("404 Not Found", "Service not found"),
+                Some(_) if registry.service_state(service_name).await.is_none() => {
+                    http::text_response("200 OK", "Unregistered")
+                }
+                Some(_) => http::text_response("200 OK", "Draining"),
+            };
+            stream.write_all(response.as_bytes()).await?;
         }
         ("GET", "/api/services") => {
-            let services = registry.list_services().await;
-            println!(
+            let services = registry.describe_services().await;
+            debug!(
                 "listing {} services for request from {}",
                 services.len(),
                 peer_addr
             );
             let json = serde_json::to_string(&services)?;
-            let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
-                json
+            stream
+                .write_all(http::json_response("200 OK", &json).as_bytes())
+                .await?;
+        }
+        ("POST", path) if path.starts_with("/api/services/") => {
+            let (name, action) = path
+                .strip_prefix("/api/services/")
+                .and_then(|p| p.rsplit_once('/'))
+                .unwrap_or(("", ""));
+            let target = match action {
+                "drain" => Lifecycle::Draining,
+                "disable" => Lifecycle::Disabled,
+                // back through probation unless that is turned off
+                "enable" => LifecycleState::enabled_state(&registry.lifecycle_settings),
+                "activate" => Lifecycle::Active,
+                _ => {
+                    stream
+                        .write_all(
+                            http::text_response(
+                                "404 Not Found",
+                                "Unknown action, expected one of: drain, disable, enable, activate",
+                            )
+                            .as_bytes(),
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let Some(state) = registry.service_state(name).await else {
+                stream
+                    .write_all(http::text_response("404 Not Found", "Service not found").as_bytes())
+                    .await?;
+                return Ok(());
+            };
+            // noted first, a drain without requests in flight is done right away
+            registry.note_drain_request(name, action == "drain");
+            let reason = format!("{} requested by {}", action, peer_addr.ip());
+            registry.set_lifecycle(name, target, reason).await;
+            let json = serde_json::json!({
+                "name": name,
+                "state": state.lifecycle.lock().unwrap().state(),
+                "in_flight": state.in_flight(),
+            });
+            stream
+                .write_all(http::json_response("200 OK", &json.to_string()).as_bytes())
+                .await?;
+        }
+        ("GET", "/api/strategy") => {
+            let json = serde_json::json!({
+                "strategy": registry.strategy().name(),
+                "available": strategy::STRATEGY_NAMES,
+            });
+            stream
+                .write_all(http::json_response("200 OK", &json.to_string()).as_bytes())
+                .await?;
+        }
+        ("GET", "/api/log-level") => {
+            let json = serde_json::json!({
+                "level": log::level(),
+                "available": log::LEVEL_NAMES,
+            });
+            stream
+                .write_all(http::json_response("200 OK", &json.to_string()).as_bytes())
+                .await?;
+        }
+        ("PUT", "/api/log-level") => {
+            let requested = serde_json::from_slice::<serde_json::Value>(body)
+                .ok()
+                .and_then(|v| v.get("level")?.as_str().map(str::to_string));
+            match requested.as_deref().map(str::parse::<log::Level>) {
+                Some(Ok(level)) => {
+                    // logged before the change so it shows up when lowering the level too
+                    info!(
+                        "log level change request from {}: {} -> {}",
+                        peer_addr,
+                        log::level(),
+                        level
+                    );
+                    log::set_level(level);
+                    stream
+                        .write_all(http::text_response("200 OK", "Log level updated").as_bytes())
+                        .await?;
+                }
+                _ => {
+                    warn!(
+                        "invalid log level change request from {}: {:?}",
+                        peer_addr, requested
+                    );
+                    let message = format!(
+                        "Unknown log level, expected one of: {}",
+                        log::LEVEL_NAMES.join(", ")
+                    );
+                    stream
+                        .write_all(http::text_response("400 Bad Request", &message).as_bytes())
+                        .await?;
+                }
+            }
+        }
+        ("PUT", "/api/strategy") => {
+            let requested = serde_json::from_slice::<serde_json::Value>(body)
+                .ok()
+                .and_then(|v| v.get("strategy")?.as_str().map(str::to_string));
+            match requested.as_deref().and_then(strategy::from_name) {
+                Some(new_strategy) => {
+                    info!(
+                        "strategy change request from {}: {}",
+                        peer_addr,
+                        new_strategy.name()
+                    );
+                    registry.set_strategy(new_strategy);
+                    stream
+                        .write_all(http::text_response("200 OK", "Strategy updated").as_bytes())
+                        .await?;
+                }
+                None => {
+                    warn!(
+                        "invalid strategy change request from {}: {:?}",
+                        peer_addr, requested
+                    );
+                    let message = format!(
+                        "Unknown strategy, expected one of: {}",
+                        strategy::STRATEGY_NAMES.join(", ")
+                    );
+                    stream
+                        .write_all(http::text_response("400 Bad Request", &message).as_bytes())
+                        .await?;
+                }
+            }
+        }
+        ("GET", "/api/usage") => {
+            let Some(filter) = usage_filter(query) else {
+                stream
+                    .write_all(
+                        http::text_response(
+                            "400 Bad Request",
+                            "Expected since/until as unix seconds, window as e.g. 24h, 7d or month",
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                return Ok(());
+            };
+            let report = registry.usage.report(&filter);
+            debug!(
+                "reporting usage of {} keys on {} services for request from {}",
+                report.keys.len(),
+                report.services.len(),
+                peer_addr
             );
+            let json = serde_json::to_string(&report)?;
+            stream
+                .write_all(http::json_response("200 OK", &json).as_bytes())
+                .await?;
+        }
+        ("GET", "/api/keys") => {
+            let keys: Vec<KeyStatus> = registry
+                .keys
+                .list()
+                .into_iter()
+                .map(|key| KeyStatus {
+                    limits: registry.limiter.limits_for(&key.name),
+                    month_tokens: registry.usage.month_tokens(&key.name),
+                    key,
+                })
+                .collect();
+            debug!(
+                "listing {} api keys for request from {}",
+                keys.len(),
+                peer_addr
+            );
+            let json = serde_json::to_string(&keys)?;
+            stream
+                .write_all(http::json_response("200 OK", &json).as_bytes())
+                .await?;
+        }
+        ("POST", "/api/keys") => {
+            let request = serde_json::from_slice::<serde_json::Value>(body).ok();
+            let name = request
+                .as_ref()
+                .and_then(|r| r.get("name")?.as_str())
+                .filter(|name| !name.is_empty());
+            let Some(name) = name else {
+                warn!("invalid api key request from {}", peer_addr);
+                stream
+                    .write_all(
+                        http::text_response("400 Bad Request", "Expected {\"name\": ...}")
+                            .as_bytes(),
+                    )
+                    .await?;
+                return Ok(());
+            };
+            // a key may be supplied to import an existing one, otherwise one is generated
+            let key = request
+                .as_ref()
+                .and_then(|r| r.get("key")?.as_str())
+                .map(str::to_string)
+                .unwrap_or_else(auth::generate_key);
+            info!(
+                "adding api key '{}' ({}) for request from {}",
+                name,
+                auth::mask_key(&key),
+                peer_addr
+            );
+            registry.keys.insert(name.to_string(), key.clone());
+            // limits for this key only, when left out the configured defaults apply
+            if let Some(limits) = request
+                .as_ref()
+                .filter(|r| {
+                    ["requests_per_minute", "tokens_per_minute", "monthly_tokens"]
+                        .iter()
+                        .any(|field| r.get(field).is_some())
+                })
+                .and_then(|r| serde_json::from_value::<TenantLimits>(r.clone()).ok())
+            {
+                registry.limiter.set_limits(name, limits);
+            }
+            let json = serde_json::json!({
+                "name": name,
+                "key": key,
+                "limits": registry.limiter.limits_for(name),
+            });
+            stream
+                .write_all(http::json_response("201 Created", &json.to_string()).as_bytes())
+                .await?;
+        }
+        ("PUT", path) if path.starts_with("/api/keys/") && path.ends_with("/limits") => {
+            let name = path
+                .strip_prefix("/api/keys/")
+                .and_then(|p| p.strip_suffix("/limits"))
+                .unwrap_or("");
+            if !registry.keys.list().iter().any(|key| key.name == name) {
+                stream
+                    .write_all(http::text_response("404 Not Found", "Key not found").as_bytes())
+                    .await?;
+                return Ok(());
+            }
+            match serde_json::from_slice::<TenantLimits>(body) {
+                Ok(limits) => {
+                    info!(
+                        "setting limits for api key '{}' to {} requests/min, {} tokens/min, {} tokens/month for request from {}",
+                        name,
+                        limits.requests_per_minute,
+                        limits.tokens_per_minute,
+                        limits.monthly_tokens,
+                        peer_addr
+                    );
+                    registry.limiter.set_limits(name, limits);
+                    let json = serde_json::json!({ "name": name, "limits": limits });
+                    stream
+                        .write_all(http::json_response("200 OK", &json.to_string()).as_bytes())
+                        .await?;
+                }
+                Err(e) => {
+                    warn!("invalid limits request from {}: {}", peer_addr, e);
+                    stream
+                        .write_all(
+                            http::text_response(
+                                "400 Bad Request",
+                                "Expected {\"requests_per_minute\": ..., \"tokens_per_minute\": ..., \"monthly_tokens\": ...}",
+                            )
+                            .as_bytes(),
+                        )
+                        .await?;
+                }
+            }
+        }
+        ("DELETE", path) if path.starts_with("/api/keys/") => {
+            let name = path.strip_prefix("/api/keys/").unwrap_or("");
+            let revoked = registry.keys.revoke(name);
+            registry.limiter.clear_limits(name);
+            info!(
+                "revoked {} api keys named '{}' for request from {}",
+                revoked, name, peer_addr
+            );
+            let response = if revoked > 0 {
+                http::text_response("200 OK", "Revoked")
+            } else {
+                http::text_response("404 Not Found", "Key not found")
+            };
             stream.write_all(response.as_bytes()).await?;
         }
         _ => {
-            println!(
+            warn!(
                 "unknown api request from {}: {} {}",
                 peer_addr, method, path
             );
-            stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
+            stream
+                .write_all(http::empty_response("404 Not Found").as_bytes())
+                .await?;
         }
     }
     Ok(())
@@ -286,112 +1307,1066 @@ async fn handle_api_request(
 async fn handle_client(
     mut stream: TcpStream,
     registry: Arc<ServiceRegistry>,
+    config: Arc<Config>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // client's address - peer address - here for logging purposes only
     let peer_addr = stream
         .peer_addr()
         .unwrap_or_else(|_| "unknown".parse().unwrap());
-    println!("handling connection from {}", peer_addr);
+    debug!("handling connection from {}", peer_addr);
 
-    // read the http request to a tuple
-    let (headers, body) = read_request(&mut stream, peer_addr).await?;
+    // bytes read from the client but not consumed yet - may hold a pipelined request
+    let mut buffer = Vec::new();
+    let mut served = 0;
 
-    let request_line = headers.lines().next().unwrap_or("");
-    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    loop {
+        // wait for the next request on a kept-alive connection
+        if served > 0 && buffer.is_empty() {
+            match tokio::time::timeout(config.client_idle_timeout, stream.readable()).await {
+                Ok(ready) => ready?,
+                Err(_) => {
+                    debug!(
+                        "closing idle connection from {} after {} requests",
+                        peer_addr, served
+                    );
+                    break;
+                }
+            }
+        }
 
-    if parts.len() != 3 {
-        println!("invalid request line from {}: {}", peer_addr, request_line);
-        stream
-            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
-            .await?;
-        return Ok(());
+        // read the full http request - headers and then the (de-chunked) body, under the
+        // request's deadline so a client stalling its upload can't hold the connection
+        let header_timeout = timeouts::enabled(config.timeouts.client_header);
+        let read = async {
+            let mut request =
+                http::read_request_head(&mut stream, &mut buffer, &config.limits, header_timeout)
+                    .await?;
+            let timeouts =
+                RequestTimeouts::new(&config.timeouts, request.header(timeouts::OVERRIDE_HEADER));
+            let body =
+                http::read_request_body(&mut stream, &mut buffer, &mut request, &config.limits);
+            timeouts
+                .run(
+                    timeouts::enabled(config.timeouts.client_body),
+                    TimeoutKind::ClientBody,
+                    body,
+                )
+                .await
+                .map_err(|_| http::ParseError::Timeout(TimeoutKind::ClientBody))??;
+            Ok::<_, http::ParseError>((request, timeouts))
+        };
+        let (mut request, timeouts) = match read.await {
+            Ok(read) => read,
+            Err(http::ParseError::ConnectionClosed) => {
+                debug!(
+                    "client {} closed the connection after {} requests",
+                    peer_addr, served
+                );
+                break;
+            }
+            Err(e) => {
+                warn!("failed to read request from {}: {}", peer_addr, e);
+                match e {
+                    http::ParseError::Timeout(TimeoutKind::ClientHeader) => {
+                        registry.metrics.record_client_header_timeout()
+                    }
+                    http::ParseError::Timeout(TimeoutKind::ClientBody) => {
+                        registry.metrics.record_client_body_timeout()
+                    }
+                    _ => {}
+                }
+                if let Some(status) = e.status() {
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    );
+                    stream.write_all(response.as_bytes()).await?;
+                }
+                break;
+            }
+        };
+        served += 1;
+
+        // the client's request id is kept so its logs line up with the backend's,
+        // otherwise one is made up - either way it is forwarded and echoed back
+        let request_id = request
+            .header("x-request-id")
+            .and_then(log::valid_request_id)
+            .map(str::to_string)
+            .unwrap_or_else(log::generate_request_id);
+        request.remove_header("x-request-id");
+        request
+            .headers
+            .push(("X-Request-Id".to_string(), request_id.clone()));
+
+        let keep_alive = log::with_request_id(request_id, async {
+            debug!(
+                "read request #{} from {}: {} headers, body {} bytes",
+                served,
+                peer_addr,
+                request.headers.len(),
+                request.body.len()
+            );
+            let client_keep_alive = request.wants_keep_alive();
+            handle_request(
+                &mut stream,
+                &mut request,
+                client_keep_alive,
+                timeouts,
+                &registry,
+                &config,
+                peer_addr,
+            )
+            .await
+        })
+        .await?;
+        if !keep_alive {
+            break;
+        }
     }
 
-    let method = parts[0];
-    let path = parts[1];
-    println!("request from {}: {} {}", peer_addr, method, path);
+    Ok(())
+}
+
+// per-request data threaded through the forwarding path
+struct RequestContext<'a> {
+    request: &'a http::HttpRequest,
+    peer_addr: std::net::SocketAddr,
+    client_keep_alive: bool,
+    stream_requested: bool,
+    // name of the api key the request was made with
+    api_key: Option<String>,
+    // headers the balancer adds to the relayed response, such as `x-ratelimit-*`
+    response_headers: Vec<(&'static str, String)>,
+    timeouts: RequestTimeouts,
+}
+
+impl RequestContext<'_> {
+    // who made the request, for log lines
+    fn client(&self) -> String {
+        match &self.api_key {
+            Some(name) => format!("{} (key '{}')", self.peer_addr, name),
+            None => self.peer_addr.to_string(),
+        }
+    }
+}
 
-    // handle api requests
-    if path.starts_with("/api/") {
-        return handle_api_request(stream, registry, method, path, &body, peer_addr).await;
+// why an `/api/*` request may not be served, `None` if it may
+fn admin_rejection(
+    request: &http::HttpRequest,
+    peer_addr: std::net::SocketAddr,
+    config: &Config,
+) -> Option<(&'static str, &'static str)> {
+    if !config.admin_allowed.is_empty()
+        && !config
+            .admin_allowed
+            .iter()
+            .any(|range| range.contains(peer_addr.ip()))
+    {
+        return Some(("403 Forbidden", "Source address not allowed"));
     }
 
-    // only handle chat completions for load balancing
-    if method != "POST" || path != "/v1/chat/completions" {
-        println!(
-            "unsupported request from {}: {} {}",
-            peer_addr, method, path
-        );
-        stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
-        return Ok(());
+    let expected = config.admin_token.as_deref()?;
+    let token = request.header("authorization").and_then(auth::bearer_token);
+    match token {
+        Some(token) if auth::constant_time_eq(token, expected) => None,
+        Some(_) => Some(("401 Unauthorized", "Invalid admin token")),
+        None => Some(("401 Unauthorized", "Admin token required")),
     }
+}
 
-    let services = registry.list_services().await;
-    println!("available services for load balancing: {}", services.len());
+// serves a single request, returns whether the client connection can be reused
+async fn handle_request(
+    stream: &mut TcpStream,
+    request: &mut http::HttpRequest,
+    client_keep_alive: bool,
+    timeouts: RequestTimeouts,
+    registry: &ServiceRegistry,
+    config: &Config,
+    peer_addr: std::net::SocketAddr,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    debug!(
+        "request from {}: {} {}",
+        peer_addr, request.method, request.path
+    );
+
+    // handle api requests - `/metrics` is protected like the admin routes
+    if request.path.starts_with("/api/") || request.path == "/metrics" {
+        if let Some((status, message)) = admin_rejection(request, peer_addr, config) {
+            warn!(
+                "rejecting admin request from {}: {} {} - {}",
+                peer_addr, request.method, request.path, message
+            );
+            stream
+                .write_all(http::text_response(status, message).as_bytes())
+                .await?;
+            return Ok(client_keep_alive);
+        }
+        handle_api_request(
+            stream,
+            registry,
+            &request.method,
+            &request.path,
+            &request.body,
+            peer_addr,
+        )
+        .await?;
+        return Ok(client_keep_alive);
+    }
+
+    // inference routes need a known api key once keys are required
+    let api_key = if config.require_api_key && request.path.starts_with("/v1/") {
+        let token = request.header("authorization").and_then(auth::bearer_token);
+        match token.and_then(|token| registry.keys.authenticate(token)) {
+            Some(name) => Some(name),
+            None => {
+                let message = match token {
+                    Some(token) => {
+                        warn!(
+                            "rejecting request from {}: unknown api key {}",
+                            peer_addr,
+                            auth::mask_key(token)
+                        );
+                        format!("Incorrect API key provided: {}.", auth::mask_key(token))
+                    }
+                    None => {
+                        warn!("rejecting request from {}: no api key", peer_addr);
+                        "You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY).".to_string()
+                    }
+                };
+                let body = http::openai_error(
+                    &message,
+                    "invalid_request_error",
+                    None,
+                    Some("invalid_api_key"),
+                );
+                stream
+                    .write_all(
+                        http::json_response_with_headers(
+                            "401 Unauthorized",
+                            &body,
+                            &[("WWW-Authenticate", "Bearer".to_string())],
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                return Ok(client_keep_alive);
+            }
+        }
+    } else {
+        None
+    };
+    // the balancer's keys are not meant for the backends
+    if api_key.is_some() {
+        request.remove_header("authorization");
+    }
 
-    let selected_service = match select_service(&services) {
-        Some(service) => service,
-        None => {
-            println!("no services available for request from {}", peer_addr);
+    // a key that used up its monthly tokens is turned away until the next month
+    if let Some(name) = &api_key {
+        let quota = registry.limiter.limits_for(name).monthly_tokens;
+        let used = registry.usage.month_tokens(name);
+        if quota > 0 && used >= quota {
+            let now = time::unix_now();
+            let next_month = usage::month_start(usage::month_start(now) + 32 * 86_400);
+            warn!(
+                "rejecting request from {} (key '{}'): monthly quota of {} tokens used up ({} used)",
+                peer_addr, name, quota, used
+            );
+            let body = http::openai_error(
+                "You exceeded your current quota, please check your plan and billing details.",
+                "insufficient_quota",
+                None,
+                Some("insufficient_quota"),
+            );
             stream
-                .write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n")
+                .write_all(
+                    http::json_response_with_headers(
+                        "429 Too Many Requests",
+                        &body,
+                        &[("Retry-After", (next_month - now).to_string())],
+                    )
+                    .as_bytes(),
+                )
                 .await?;
-            return Ok(());
+            return Ok(client_keep_alive);
         }
+    }
+
+    let request = &*request;
+    let method = request.method.as_str();
+    let path = request.path.as_str();
+
+    // only the endpoints in the routing table are balanced
+    let Some(route) = routes::find(method, path) else {
+        warn!(
+            "unsupported request from {}: {} {}",
+            peer_addr, method, path
+        );
+        let status = if routes::path_known(path) {
+            "405 Method Not Allowed"
+        } else {
+            "404 Not Found"
+        };
+        stream
+            .write_all(http::empty_response(status).as_bytes())
+            .await?;
+        return Ok(client_keep_alive);
     };
 
-    let address = match registry.get_service_address(&selected_service.name).await {
-        Some(addr) => addr,
-        None => {
-            println!(
-                "failed to resolve address for service: {}",
-                selected_service.name
+    // requests and tokens per minute are limited per api key, charged only for requests
+    // that go to a backend - the merged model list is answered here
+    let limited_key = api_key.as_ref().filter(|_| !route.aggregate);
+    let mut response_headers = match limited_key {
+        Some(name) => match registry.limiter.acquire(name) {
+            Ok(status) => status.headers(),
+            Err(limited) => {
+                let retry_after = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                warn!(
+                    "rate limiting request from {} (key '{}'): {} per minute exhausted, retry in {}s",
+                    peer_addr, name, limited.limit, retry_after
+                );
+                let body = http::openai_error(
+                    &format!(
+                        "Rate limit reached for {} per minute on key '{}'. Please try again in {}s.",
+                        limited.limit, name, retry_after
+                    ),
+                    limited.limit,
+                    None,
+                    Some("rate_limit_exceeded"),
+                );
+                let mut headers = limited.status.headers();
+                headers.push(("Retry-After", retry_after.to_string()));
+                stream
+                    .write_all(
+                        http::json_response_with_headers("429 Too Many Requests", &body, &headers)
+                            .as_bytes(),
+                    )
+                    .await?;
+                return Ok(client_keep_alive);
+            }
+        },
+        None => Vec::new(),
+    };
+    if let Some(request_id) = request.header("x-request-id") {
+        response_headers.push(("X-Request-Id", request_id.to_string()));
+    }
+    // the model list is put together from every backend instead of asking just one
+    if route.aggregate {
+        let list = registry.list_models(&config.models, &config.breaker).await;
+        let count = list["data"].as_array().map_or(0, |models| models.len());
+        info!(
+            "answering {} {} from {} with {} models across services",
+            method, path, peer_addr, count
+        );
+        stream
+            .write_all(
+                http::json_response_with_headers("200 OK", &list.to_string(), &response_headers)
+                    .as_bytes(),
+            )
+            .await?;
+        return Ok(client_keep_alive);
+    }
+
+    let payload: serde_json::Value = match serde_json::from_slice(&request.body) {
+        Ok(payload) => payload,
+        Err(_) if !route.json_body => serde_json::Value::Null,
+        Err(e) => {
+            warn!("invalid json body from {}: {}", peer_addr, e);
+            let body = http::openai_error(
+                "We could not parse the JSON body of your request.",
+                "invalid_request_error",
+                None,
+                None,
             );
             stream
-                .write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n")
+                .write_all(http::json_response("400 Bad Request", &body).as_bytes())
                 .await?;
-            return Ok(());
+            return Ok(client_keep_alive);
         }
     };
+    let model = payload.get("model").and_then(|m| m.as_str());
+    let stream_requested = payload
+        .get("stream")
+        .and_then(|s| s.as_bool())
+        .unwrap_or(false);
 
-    println!(
-        "forwarding request from {} to service '{}' at {}",
-        peer_addr, selected_service.name, address
+    let services = registry.pool_services(route.pool).await;
+    let total_services = services.len();
+
+    // only services that serve the requested model take part in the selection
+    let services: Vec<Service> = match model {
+        Some(model) => services
+            .into_iter()
+            .filter(|s| s.serves_model(model))
+            .collect(),
+        None => services,
+    };
+    debug!(
+        "available services for {} {}: {} of {} in pool '{}' (model: {})",
+        method,
+        path,
+        services.len(),
+        total_services,
+        route.pool,
+        model.unwrap_or("unspecified")
     );
 
-    match TcpStream::connect(&address).await {
-        Ok(mut backend_stream) => {
-            backend_stream.write_all(headers.as_bytes()).await?;
-            backend_stream.write_all(b"\r\n\r\n").await?;
-            backend_stream.write_all(&body).await?;
+    if services.is_empty()
+        && total_services > 0
+        && let Some(model) = model
+    {
+        warn!(
+            "no service serves model '{}' for request from {}",
+            model, peer_addr
+        );
+        let body = http::openai_error(
+            &format!("The model `{}` does not exist", model),
+            "invalid_request_error",
+            Some("model"),
+            Some("model_not_found"),
+        );
+        stream
+            .write_all(http::json_response("404 Not Found", &body).as_bytes())
+            .await?;
+        return Ok(client_keep_alive);
+    }
+
+    // backends failing their health probes, ejected for failing requests or with an
+    // open circuit are skipped
+    let routable = services.len();
+    let services = registry.eligible_services(services, &config.breaker).await;
+    if services.len() < routable {
+        debug!(
+            "skipping {} unhealthy, ejected or circuit-broken services for request from {}",
+            routable - services.len(),
+            peer_addr
+        );
+    }
+
+    // conversational clients stick to one backend so its prompt cache stays warm
+    let session_key = if config.session_affinity {
+        request
+            .header("x-session-id")
+            .or_else(|| payload.get("user").and_then(|u| u.as_str()))
+            .filter(|key| !key.is_empty())
+    } else {
+        None
+    };
+
+    // requests sharing a long prompt prefix go where its kv cache is likely still warm
+    let prefix_key = if config.prefix.prefix_bytes > 0 {
+        affinity::prefix_key(&payload, config.prefix.prefix_bytes)
+    } else {
+        None
+    };
+
+    let ctx = RequestContext {
+        request,
+        peer_addr,
+        client_keep_alive,
+        stream_requested,
+        api_key,
+        response_headers,
+        timeouts,
+    };
+
+    // backends that already failed this request are left out of the next draw
+    let mut excluded: Vec<String> = Vec::new();
+    let max_attempts = config.retry.max_attempts.max(1);
+    let mut attempt = 0;
 
-            let bytes_copied = tokio::io::copy(&mut backend_stream, &mut stream).await?;
-            println!(
-                "completed request from {} via '{}' - {} bytes returned",
-                peer_addr, selected_service.name, bytes_copied
+    loop {
+        let candidates: Vec<Service> = services
+            .iter()
+            .filter(|s| !excluded.contains(&s.name))
+            .cloned()
+            .collect();
+
+        // backends at their `max_concurrency` are waited for, within the queue limits
+        let candidates = match registry.wait_for_capacity(&candidates, &config.queue).await {
+            Ok(candidates) => candidates,
+            Err(rejection) => {
+                let (status, message, code) = match rejection {
+                    QueueRejection::Full => (
+                        "429 Too Many Requests",
+                        "All backends are busy and the wait queue is full, please retry later.",
+                        "queue_full",
+                    ),
+                    QueueRejection::TimedOut => (
+                        "503 Service Unavailable",
+                        "Timed out waiting for a free backend, please retry later.",
+                        "queue_timeout",
+                    ),
+                };
+                warn!(
+                    "rejecting request from {}: {} ({} requests waiting)",
+                    peer_addr,
+                    code,
+                    registry.queue.waiting()
+                );
+                let body = http::openai_error(message, "server_error", None, Some(code));
+                let retry_after = config.queue.retry_after.as_secs().max(1).to_string();
+                stream
+                    .write_all(
+                        http::json_response_with_headers(
+                            status,
+                            &body,
+                            &[("Retry-After", retry_after)],
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                return Ok(client_keep_alive);
+            }
+        };
+
+        let sticky = session_key.and_then(|key| registry.sticky_service(key, &candidates));
+        if let (Some(key), Some(service)) = (session_key, &sticky) {
+            debug!("session '{}' pinned to service '{}'", key, service.name);
+        }
+        let cached = match (&sticky, prefix_key) {
+            (None, Some(key)) => {
+                let service = registry
+                    .prefix_service(key, &candidates, &config.prefix)
+                    .await;
+                if let Some(service) = &service {
+                    debug!(
+                        "prefix {:016x} recently served by '{}', routing there",
+                        key, service.name
+                    );
+                }
+                service
+            }
+            _ => None,
+        };
+        let selected = match sticky.or(cached) {
+            Some(service) => Some(service),
+            None => registry.select_service(&candidates).await,
+        };
+
+        let selected_service = match selected {
+            Some(service) => service,
+            None => {
+                warn!(
+                    "no services available for request from {} (attempt {}/{}, {} excluded)",
+                    peer_addr,
+                    attempt + 1,
+                    max_attempts,
+                    excluded.len()
+                );
+                stream
+                    .write_all(http::empty_response("503 Service Unavailable").as_bytes())
+                    .await?;
+                return Ok(client_keep_alive);
+            }
+        };
+
+        // counted as in flight until the response is relayed or the attempt fails -
+        // another request may have taken the last free slot meanwhile
+        let Some(_slot) = registry.reserve(&selected_service).await else {
+            continue;
+        };
+
+        // another request may have taken the last half-open trial slot meanwhile
+        if !registry
+            .admit(&selected_service.name, &config.breaker)
+            .await
+        {
+            info!(
+                "service '{}' stopped taking requests or its circuit has no free trial slots, trying another service",
+                selected_service.name
             );
+            excluded.push(selected_service.name);
+            continue;
         }
-        Err(e) => {
-            println!(
-                "failed to connect to service '{}' at {}: {}",
-                selected_service.name, address, e
+
+        attempt += 1;
+        registry.metrics.record_selection(&selected_service.name);
+        debug!(
+            "attempt {}/{} for request from {}: trying service '{}'",
+            attempt, max_attempts, peer_addr, selected_service.name
+        );
+
+        let relay = forward_to_service(stream, &ctx, &selected_service, registry, config).await;
+        let timeout = match &relay {
+            Ok(stats) => stats.client_timeout,
+            Err(e) => match e.error {
+                http::ParseError::Timeout(kind) => Some(kind),
+                _ => None,
+            },
+        };
+        if let Some(kind) = timeout {
+            registry
+                .metrics
+                .record_timeout(&selected_service.name, kind);
+        }
+
+        let failure = match &relay {
+            // a hang-up is the client's doing, not a backend failure
+            Ok(stats) if stats.client_closed => None,
+            Ok(stats) if stats.status >= 500 => Some(format!("http {}", stats.status)),
+            Ok(_) => None,
+            Err(e) => Some(e.to_string()),
+        };
+        registry
+            .record_circuit(&selected_service.name, failure.as_deref(), &config.breaker)
+            .await;
+        registry
+            .record_probation(&selected_service.name, failure.is_some())
+            .await;
+        registry
+            .record_outcome(&selected_service.name, failure, &config.outlier)
+            .await;
+        registry.metrics.record_attempt(
+            &selected_service.name,
+            request.body.len(),
+            relay.as_ref().ok(),
+        );
+        // nothing reached the client yet, so another backend can still be tried - unless
+        // this one took too long to answer, the request may well take as long elsewhere and
+        // the deadline is shared by all attempts
+        let retried = match &relay {
+            Err(e) if !e.response_started => {
+                let timed_out = matches!(
+                    e.error,
+                    http::ParseError::Timeout(kind) if kind != TimeoutKind::Connect
+                );
+                attempt < max_attempts && !timed_out
+            }
+            _ => false,
+        };
+        // usage is accounted once per client request, to the service that answered it
+        if !retried {
+            let (usage, tokens, failed) = match &relay {
+                Ok(stats) => (
+                    stats.usage.as_ref(),
+                    stats.tokens_used(),
+                    stats.status >= 400,
+                ),
+                Err(_) => (None, 0, true),
+            };
+            registry.usage.record(
+                ctx.api_key.as_deref(),
+                &selected_service.name,
+                usage,
+                tokens,
+                failed,
             );
-            stream
-                .write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n")
-                .await?;
+            if let Some(name) = &ctx.api_key {
+                registry.limiter.consume_tokens(name, tokens);
+            }
+        }
+        if let Ok(stats) = &relay
+            && stats.status < 500
+        {
+            registry
+                .record_latency(&selected_service.name, stats, &config.latency)
+                .await;
+            if let Some(key) = prefix_key {
+                registry
+                    .prefixes
+                    .remember(key, &selected_service.name, &config.prefix);
+            }
+        }
+
+        match relay {
+            Ok(stats) => {
+                if let Some(kind) = stats.client_timeout {
+                    warn!(
+                        "client {} stopped reading ({} timeout), closed connection to '{}' after {} events, {} bytes, {}ms",
+                        ctx.client(),
+                        kind,
+                        selected_service.name,
+                        stats.events,
+                        stats.body_bytes,
+                        stats.total.as_millis()
+                    );
+                } else if stats.client_closed {
+                    // the backend connection was dropped so the backend stops generating
+                    info!(
+                        "client {} disconnected, closed connection to '{}' after {} events, {} bytes, {}ms",
+                        ctx.client(),
+                        selected_service.name,
+                        stats.events,
+                        stats.body_bytes,
+                        stats.total.as_millis()
+                    );
+                } else if stats.streaming {
+                    info!(
+                        "completed stream from {} via '{}' - status {}, ttft {}, {} chunks, {} bytes, {}ms total",
+                        ctx.client(),
+                        selected_service.name,
+                        stats.status,
+                        stats
+                            .time_to_first_token
+                            .map(|t| format!("{}ms", t.as_millis()))
+                            .unwrap_or_else(|| "n/a".to_string()),
+                        stats.events,
+                        stats.body_bytes,
+                        stats.total.as_millis()
+                    );
+                } else {
+                    info!(
+                        "completed request from {} via '{}' - status {}, {} bytes returned, ttfb {}ms, {}ms total",
+                        ctx.client(),
+                        selected_service.name,
+                        stats.status,
+                        stats.body_bytes,
+                        stats.time_to_first_byte.as_millis(),
+                        stats.total.as_millis()
+                    );
+                }
+                return Ok(stats.keep_alive);
+            }
+            Err(e) if e.response_started => {
+                // the client got a partial response, the connection can't be reused
+                warn!(
+                    "relaying response from '{}' to {} failed: {}",
+                    selected_service.name, peer_addr, e
+                );
+                return Ok(false);
+            }
+            Err(e) => {
+                warn!(
+                    "attempt {}/{} for request from {} failed on service '{}': {}",
+                    attempt, max_attempts, peer_addr, selected_service.name, e
+                );
+                if !retried {
+                    warn!(
+                        "giving up on request from {} after {} attempts",
+                        peer_addr, attempt
+                    );
+                    let status = match e.error {
+                        http::ParseError::Io(_) => "503 Service Unavailable",
+                        http::ParseError::Timeout(_) => "504 Gateway Timeout",
+                        _ => "502 Bad Gateway",
+                    };
+                    stream
+                        .write_all(http::empty_response(status).as_bytes())
+                        .await?;
+                    return Ok(client_keep_alive);
+                }
+                excluded.push(selected_service.name.clone());
+            }
         }
     }
+}
 
-    Ok(())
+// one forwarding attempt against a single service
+//
+// connect failures are reported like a backend failure before the response started,
+// so the caller can treat every `Err` with `response_started == false` as retryable
+async fn forward_to_service(
+    stream: &mut TcpStream,
+    ctx: &RequestContext<'_>,
+    service: &Service,
+    registry: &ServiceRegistry,
+    config: &Config,
+) -> Result<proxy::RelayStats, proxy::RelayError> {
+    let unavailable = |message: String| proxy::RelayError {
+        error: http::ParseError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            message,
+        )),
+        response_started: false,
+    };
+
+    let (address, state) = match (
+        registry.get_service_address(&service.name).await,
+        registry.service_state(&service.name).await,
+    ) {
+        (Some(addr), Some(state)) => (addr, state),
+        _ => {
+            return Err(unavailable(format!(
+                "failed to resolve address for service: {}",
+                service.name
+            )));
+        }
+    };
+
+    let timed_out = |kind| proxy::RelayError {
+        error: http::ParseError::Timeout(kind),
+        response_started: false,
+    };
+    let timeouts = &ctx.timeouts;
+
+    let (mut backend_stream, reused) = timeouts
+        .run(
+            timeouts.connect,
+            TimeoutKind::Connect,
+            state.pool.checkout(&address),
+        )
+        .await
+        .map_err(timed_out)?
+        .map_err(|e| {
+            unavailable(format!(
+                "failed to connect to service '{}' at {}: {}",
+                service.name, address, e
+            ))
+        })?;
+
+    debug!(
+        "forwarding request from {} to service '{}' at {} ({} connection, {} in flight)",
+        ctx.peer_addr,
+        service.name,
+        address,
+        if reused { "pooled" } else { "new" },
+        state.in_flight()
+    );
+
+    let mut response_headers = ctx.response_headers.clone();
+    response_headers.push(("X-Served-By", service.name.clone()));
+    let forwarding = http::Forwarding {
+        host: &address,
+        client_ip: ctx.peer_addr.ip(),
+        headers: &service.headers,
+        response_headers: &response_headers,
+        timeouts,
+    };
+
+    let mut relay = proxy::forward(
+        &mut backend_stream,
+        stream,
+        ctx.request,
+        ctx.stream_requested,
+        ctx.client_keep_alive,
+        &forwarding,
+        &config.limits,
+    )
+    .await;
+
+    // a pooled connection may have been closed by the backend while idle -
+    // nothing reached the client yet, so retry once on a fresh connection
+    if reused
+        && let Err(e) = &relay
+        && !e.response_started
+    {
+        warn!(
+            "pooled connection to '{}' failed ({}), retrying on a new connection",
+            service.name, e
+        );
+        backend_stream = timeouts
+            .run(
+                timeouts.connect,
+                TimeoutKind::Connect,
+                TcpStream::connect(&address),
+            )
+            .await
+            .map_err(timed_out)?
+            .map_err(|e| {
+                unavailable(format!(
+                    "failed to connect to service '{}' at {}: {}",
+                    service.name, address, e
+                ))
+            })?;
+        relay = proxy::forward(
+            &mut backend_stream,
+            stream,
+            ctx.request,
+            ctx.stream_requested,
+            ctx.client_keep_alive,
+            &forwarding,
+            &config.limits,
+        )
+        .await;
+    }
+
+    if let Ok(stats) = &relay
+        && stats.backend_reusable
+    {
+        state.pool.checkin(backend_stream);
+    }
+    relay
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    println!("initializing load-balancer...");
+    info!("initializing load-balancer...");
+
+    let config = match Config::from_env() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!("invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    log::set_level(config.log_level);
+    info!("log level: {}", config.log_level);
+    info!(
+        "request limits: headers {} bytes, body {} bytes",
+        config.limits.max_header_bytes, config.limits.max_body_bytes
+    );
+    info!(
+        "connection pool: {} idle per service, idle timeout {}s, client idle timeout {}s",
+        config.pool.max_idle,
+        config.pool.idle_timeout.as_secs(),
+        config.client_idle_timeout.as_secs()
+    );
+    info!(
+        "retry policy: up to {} attempts per request",
+        config.retry.max_attempts
+    );
 
     // `let registry = Arc::new(ServiceRegistry::new())` could be used due to wasm's single threaded nature
     // but `Arc` works well with `tokio::spawn`
-    let registry = Arc::new(ServiceRegistry::new());
+    let strategy = strategy::from_name(&config.strategy).unwrap_or_else(|| {
+        error!(
+            "unknown balancing strategy '{}', falling back to weighted_random",
+            config.strategy
+        );
+        Arc::new(strategy::WeightedRandom)
+    });
+    info!("balancing strategy: {}", strategy.name());
+    if config.session_affinity {
+        info!("session affinity enabled (X-Session-Id header or `user` field)");
+    }
+    if config.prefix.prefix_bytes > 0 {
+        info!(
+            "prefix routing enabled: first {} bytes of the conversation, load factor {}",
+            config.prefix.prefix_bytes, config.prefix.load_factor
+        );
+    }
+    let registry = Arc::new(ServiceRegistry::new(
+        config.pool.clone(),
+        config.lifecycle.clone(),
+        strategy,
+        config.rate_limit,
+    ));
+
+    if let Some(path) = &config.api_keys_file {
+        match registry.keys.load_file(path) {
+            Ok(loaded) => info!("loaded {} api keys from {}", loaded, path),
+            Err(e) => error!("failed to load api keys from {}: {}", path, e),
+        }
+    }
+    match (&config.admin_token, config.admin_allowed.len()) {
+        (None, 0) => warn!("/api/* admin routes are open to any client"),
+        (token, ranges) => info!(
+            "/api/* admin routes: token {}, {} allowed source ranges",
+            if token.is_some() {
+                "required"
+            } else {
+                "not required"
+            },
+            ranges
+        ),
+    }
+    if config.require_api_key {
+        info!(
+            "api keys required on /v1/* routes ({} keys known)",
+            registry.keys.len()
+        );
+    } else {
+        info!("api keys not required on /v1/* routes");
+    }
+    info!(
+        "rate limits per api key: {} requests/min, {} tokens/min, {} tokens/month (0 is unlimited)",
+        config.rate_limit.requests_per_minute,
+        config.rate_limit.tokens_per_minute,
+        config.rate_limit.monthly_tokens
+    );
+
+    // usage is kept in memory and written out periodically when a file is configured
+    if let Some(path) = &config.usage.file {
+        match registry.usage.load_file(path) {
+            Ok(loaded) => info!("loaded {} usage records from {}", loaded, path),
+            Err(e) => error!("failed to load usage from {}: {}", path, e),
+        }
+    }
+    let usage_registry = registry.clone();
+    let usage_settings = config.usage.clone();
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(
+            usage_settings
+                .flush_interval
+                .max(std::time::Duration::from_secs(1)),
+        );
+        loop {
+            timer.tick().await;
+            usage_registry.usage.prune(&usage_settings);
+            if let Some(path) = &usage_settings.file
+                && let Err(e) = usage_registry.usage.save_file(path)
+            {
+                error!("failed to write usage to {}: {}", path, e);
+            }
+        }
+    });
+
+    // remove draining services as soon as their last request is done, and those that
+    // ran past the drain timeout
+    let drain_registry = registry.clone();
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            tokio::select! {
+                _ = drain_registry.drained.notified() => {}
+                _ = timer.tick() => {}
+            }
+            drain_registry.remove_drained().await;
+        }
+    });
+
+    // periodically close pooled backend connections that went stale
+    let sweep_registry = registry.clone();
+    let sweep_interval = config
+        .pool
+        .idle_timeout
+        .max(std::time::Duration::from_secs(1));
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(sweep_interval);
+        loop {
+            timer.tick().await;
+            sweep_registry.sweep_pools().await;
+        }
+    });
+
+    if config.outlier.max_ejection_percent == 0 {
+        info!("passive outlier detection disabled");
+    } else {
+        info!(
+            "passive outlier detection: eject after {} consecutive failures for {}s-{}s, at most {}% of services",
+            config.outlier.consecutive_errors,
+            config.outlier.base_ejection.as_secs(),
+            config.outlier.max_ejection.as_secs(),
+            config.outlier.max_ejection_percent
+        );
+    }
+
+    info!(
+        "wait queue: up to {} requests for {}s when every backend is at its concurrency limit",
+        config.queue.max_waiting,
+        config.queue.timeout.as_secs()
+    );
+    info!(
+        "circuit breaker: open after {} consecutive failures or {}% errors over {} requests, for {}s, {} trial requests",
+        config.breaker.consecutive_failures,
+        config.breaker.error_rate_percent,
+        config.breaker.window,
+        config.breaker.open_duration.as_secs(),
+        config.breaker.half_open_requests
+    );
+
+    // active health checking of every registered service
+    if config.health.interval.is_zero() {
+        info!("active health checks disabled");
+    } else {
+        info!(
+            "active health checks: GET {} every {}s, unhealthy after {} failures, healthy after {} successes",
+            config.health.path,
+            config.health.interval.as_secs(),
+            config.health.unhealthy_threshold,
+            config.health.healthy_threshold
+        );
+        let health_registry = registry.clone();
+        let health_settings = config.health.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(health_settings.interval);
+            loop {
+                timer.tick().await;
+                health_registry.run_health_checks(&health_settings).await;
+            }
+        });
+    }
 
     let addr = env::args()
         .nth(1)
@@ -400,7 +2375,7 @@ async fn main() {
     let listener = TcpListener::bind(&addr)
         .await
         .unwrap_or_else(|_| panic!("failed to bind to address: {}", addr));
-    println!("load balancer listening on: {}", addr);
+    info!("load balancer listening on: {}", addr);
 
     // loop to keep listening to new connections on the tcplistener bound address
     loop {
@@ -409,16 +2384,17 @@ async fn main() {
             // stream: The TcpStream
             // peer_addr: The SocketAddr
             Ok((stream, peer_addr)) => {
-                println!("accepted connection from: {}", peer_addr);
+                debug!("accepted connection from: {}", peer_addr);
                 let registry_clone = registry.clone();
+                let config_clone = config.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, registry_clone).await {
-                        println!("error handling client {}: {}", peer_addr, e);
+                    if let Err(e) = handle_client(stream, registry_clone, config_clone).await {
+                        info!("error handling client {}: {}", peer_addr, e);
                     }
                 });
             }
             Err(e) => {
-                eprintln!("failed to accept connection: {}", e);
+                error!("failed to accept connection: {}", e);
             }
         }
     }
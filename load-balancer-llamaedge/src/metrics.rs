@@ -0,0 +1,426 @@
+use crate::lifecycle::{LIFECYCLE_STATES, Lifecycle};
+use crate::proxy::RelayStats;
+use crate::timeouts::TimeoutKind;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// upper bounds (seconds) of the histogram buckets
+const DURATION_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+const TTFT_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    // per bucket, not cumulative - summed up when rendering
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ServiceMetrics {
+    // by status class (`2xx` ... `5xx`) or `error` when no response came back
+    requests: BTreeMap<&'static str, u64>,
+    // request bodies sent to the service and response bodies relayed from it
+    bytes_sent: u64,
+    bytes_received: u64,
+    // times the service was picked for an attempt
+    selections: u64,
+    // attempts cut off by a timeout, by `TimeoutKind::name`
+    timeouts: BTreeMap<&'static str, u64>,
+    duration: Histogram,
+    ttft: Histogram,
+}
+
+impl Default for ServiceMetrics {
+    fn default() -> Self {
+        Self {
+            requests: BTreeMap::new(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            selections: 0,
+            timeouts: BTreeMap::new(),
+            duration: Histogram::new(DURATION_BUCKETS),
+            ttft: Histogram::new(TTFT_BUCKETS),
+        }
+    }
+}
+
+// point-in-time values of a service, read from the registry when rendering
+pub struct ServiceGauges {
+    pub name: String,
+    pub in_flight: usize,
+    pub healthy: bool,
+    pub ejected: bool,
+    pub circuit_open: bool,
+    pub lifecycle: Lifecycle,
+}
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+// label values are quoted, so backslashes, quotes and newlines need escaping
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// counters and histograms for `GET /metrics`, kept per service
+#[derive(Default)]
+pub struct Metrics {
+    services: Mutex<BTreeMap<String, ServiceMetrics>>,
+    registrations: AtomicU64,
+    unregistrations: AtomicU64,
+    // clients that didn't send their request headers in time
+    client_header_timeouts: AtomicU64,
+    // clients that didn't send their request body in time
+    client_body_timeouts: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_registration(&self) {
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_unregistration(&self) {
+        self.unregistrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_client_header_timeout(&self) {
+        self.client_header_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_client_body_timeout(&self) {
+        self.client_body_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self, service: &str, kind: TimeoutKind) {
+        let mut services = self.services.lock().unwrap();
+        let metrics = services.entry(service.to_string()).or_default();
+        *metrics.timeouts.entry(kind.name()).or_default() += 1;
+    }
+
+    pub fn record_selection(&self, service: &str) {
+        let mut services = self.services.lock().unwrap();
+        services.entry(service.to_string()).or_default().selections += 1;
+    }
+
+    // a finished attempt, `stats` is `None` when the backend failed before responding
+    pub fn record_attempt(&self, service: &str, request_bytes: usize, stats: Option<&RelayStats>) {
+        let mut services = self.services.lock().unwrap();
+        let metrics = services.entry(service.to_string()).or_default();
+        metrics.bytes_sent += request_bytes as u64;
+        let Some(stats) = stats else {
+            *metrics.requests.entry("error").or_default() += 1;
+            return;
+        };
+        *metrics
+            .requests
+            .entry(status_class(stats.status))
+            .or_default() += 1;
+        metrics.bytes_received += stats.body_bytes as u64;
+        // a cut-short response says nothing about how long the backend takes
+        if !stats.client_closed {
+            metrics.duration.observe(stats.total.as_secs_f64());
+        }
+        if let Some(ttft) = stats.time_to_first_token {
+            metrics.ttft.observe(ttft.as_secs_f64());
+        }
+    }
+
+    // prometheus text exposition format
+    pub fn render(&self, gauges: &[ServiceGauges], queue_waiting: usize) -> String {
+        let mut out = String::new();
+        let services = self.services.lock().unwrap();
+        let labels = |name: &str| format!("service=\"{}\"", escape(name));
+
+        header(
+            &mut out,
+            "lb_services",
+            "gauge",
+            "Services in the registry.",
+        );
+        let _ = writeln!(out, "lb_services {}", gauges.len());
+        header(
+            &mut out,
+            "lb_service_lifecycle",
+            "gauge",
+            "The service's lifecycle state (1 for the current one).",
+        );
+        for service in gauges {
+            for state in LIFECYCLE_STATES {
+                let _ = writeln!(
+                    out,
+                    "lb_service_lifecycle{{{},state=\"{}\"}} {}",
+                    labels(&service.name),
+                    state.name(),
+                    (service.lifecycle == *state) as u8
+                );
+            }
+        }
+        header(
+            &mut out,
+            "lb_service_healthy",
+            "gauge",
+            "Whether the service passes its health checks (1) or not (0).",
+        );
+        for service in gauges {
+            let _ = writeln!(
+                out,
+                "lb_service_healthy{{{}}} {}",
+                labels(&service.name),
+                service.healthy as u8
+            );
+        }
+        header(
+            &mut out,
+            "lb_service_ejected",
+            "gauge",
+            "Whether the service is ejected for failing proxied requests (1) or not (0).",
+        );
+        for service in gauges {
+            let _ = writeln!(
+                out,
+                "lb_service_ejected{{{}}} {}",
+                labels(&service.name),
+                service.ejected as u8
+            );
+        }
+        header(
+            &mut out,
+            "lb_circuit_open",
+            "gauge",
+            "Whether the service's circuit breaker is open (1) or not (0).",
+        );
+        for service in gauges {
+            let _ = writeln!(
+                out,
+                "lb_circuit_open{{{}}} {}",
+                labels(&service.name),
+                service.circuit_open as u8
+            );
+        }
+        header(
+            &mut out,
+            "lb_in_flight_requests",
+            "gauge",
+            "Requests currently being forwarded to the service.",
+        );
+        for service in gauges {
+            let _ = writeln!(
+                out,
+                "lb_in_flight_requests{{{}}} {}",
+                labels(&service.name),
+                service.in_flight
+            );
+        }
+        header(
+            &mut out,
+            "lb_queue_waiting_requests",
+            "gauge",
+            "Requests waiting for a service below its concurrency limit.",
+        );
+        let _ = writeln!(out, "lb_queue_waiting_requests {}", queue_waiting);
+
+        header(
+            &mut out,
+            "lb_registrations_total",
+            "counter",
+            "Services registered or updated through the registry.",
+        );
+        let _ = writeln!(
+            out,
+            "lb_registrations_total {}",
+            self.registrations.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "lb_unregistrations_total",
+            "counter",
+            "Services removed from the registry.",
+        );
+        let _ = writeln!(
+            out,
+            "lb_unregistrations_total {}",
+            self.unregistrations.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "lb_selections_total",
+            "counter",
+            "Times the service was picked for an attempt.",
+        );
+        for (name, metrics) in services.iter() {
+            let _ = writeln!(
+                out,
+                "lb_selections_total{{{}}} {}",
+                labels(name),
+                metrics.selections
+            );
+        }
+        header(
+            &mut out,
+            "lb_requests_total",
+            "counter",
+            "Attempts proxied to the service by response status class, `error` when it failed before responding.",
+        );
+        for (name, metrics) in services.iter() {
+            for (class, count) in &metrics.requests {
+                let _ = writeln!(
+                    out,
+                    "lb_requests_total{{{},class=\"{}\"}} {}",
+                    labels(name),
+                    class,
+                    count
+                );
+            }
+        }
+        header(
+            &mut out,
+            "lb_timeouts_total",
+            "counter",
+            "Attempts cut off by the connect, first_byte, idle or total timeout.",
+        );
+        for (name, metrics) in services.iter() {
+            for (kind, count) in &metrics.timeouts {
+                let _ = writeln!(
+                    out,
+                    "lb_timeouts_total{{{},kind=\"{}\"}} {}",
+                    labels(name),
+                    kind,
+                    count
+                );
+            }
+        }
+        header(
+            &mut out,
+            "lb_client_header_timeouts_total",
+            "counter",
+            "Connections closed with 408 for not sending request headers in time.",
+        );
+        let _ = writeln!(
+            out,
+            "lb_client_header_timeouts_total {}",
+            self.client_header_timeouts.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "lb_client_body_timeouts_total",
+            "counter",
+            "Requests answered with 408 for not sending their body in time.",
+        );
+        let _ = writeln!(
+            out,
+            "lb_client_body_timeouts_total {}",
+            self.client_body_timeouts.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "lb_request_bytes_total",
+            "counter",
+            "Request body bytes sent to the service.",
+        );
+        for (name, metrics) in services.iter() {
+            let _ = writeln!(
+                out,
+                "lb_request_bytes_total{{{}}} {}",
+                labels(name),
+                metrics.bytes_sent
+            );
+        }
+        header(
+            &mut out,
+            "lb_response_bytes_total",
+            "counter",
+            "Response body bytes relayed from the service.",
+        );
+        for (name, metrics) in services.iter() {
+            let _ = writeln!(
+                out,
+                "lb_response_bytes_total{{{}}} {}",
+                labels(name),
+                metrics.bytes_received
+            );
+        }
+        header(
+            &mut out,
+            "lb_request_duration_seconds",
+            "histogram",
+            "Time from sending the request to the end of the response.",
+        );
+        for (name, metrics) in services.iter() {
+            metrics
+                .duration
+                .render(&mut out, "lb_request_duration_seconds", &labels(name));
+        }
+        header(
+            &mut out,
+            "lb_time_to_first_token_seconds",
+            "histogram",
+            "Time from sending a streaming request to its first event.",
+        );
+        for (name, metrics) in services.iter() {
+            metrics
+                .ttft
+                .render(&mut out, "lb_time_to_first_token_seconds", &labels(name));
+        }
+        out
+    }
+}
@@ -0,0 +1,136 @@
+use crate::http::{self, BodyDecoder, RequestLimits};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// largest `/v1/models` answer read from a backend
+const MAX_MODELS_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ModelsSettings {
+    // how long a backend's model list is reused before asking it again
+    pub cache_ttl: Duration,
+    // a backend not answering within this is left out (or its cached list is used)
+    pub timeout: Duration,
+}
+
+impl Default for ModelsSettings {
+    fn default() -> Self {
+        Self {
+            cache_ttl: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+// the last model list a backend returned
+#[derive(Debug, Default)]
+pub struct ModelCache {
+    entry: Mutex<Option<(Instant, Vec<Value>)>>,
+}
+
+impl ModelCache {
+    // the cached list if it is younger than `ttl`
+    pub fn fresh(&self, ttl: Duration) -> Option<Vec<Value>> {
+        let entry = self.entry.lock().unwrap();
+        let (at, models) = entry.as_ref()?;
+        (at.elapsed() < ttl).then(|| models.clone())
+    }
+
+    // the cached list no matter how old, for when the backend doesn't answer
+    pub fn stale(&self) -> Option<Vec<Value>> {
+        self.entry
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, models)| models.clone())
+    }
+
+    pub fn store(&self, models: Vec<Value>) {
+        *self.entry.lock().unwrap() = Some((Instant::now(), models));
+    }
+
+    pub fn clear(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+}
+
+// the `data` entries of a backend's `GET /v1/models`, asked for with its injected `headers`
+pub async fn fetch(
+    address: &str,
+    headers: &BTreeMap<String, String>,
+) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(address).await?;
+    let mut request = format!(
+        "GET /v1/models HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n",
+        address
+    );
+    http::push_injected_headers(&mut request, headers);
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    let head = http::read_response_head(&mut stream, &mut buf, &RequestLimits::default()).await?;
+    if !(200..300).contains(&head.status) {
+        return Err(format!("http {}", head.status).into());
+    }
+    let mut decoder = BodyDecoder::new(head.body_kind("GET"));
+    let mut body = Vec::new();
+    while let Some(data) = decoder.next(&mut stream, &mut buf).await? {
+        body.extend_from_slice(&data);
+        if body.len() > MAX_MODELS_BODY {
+            return Err("model list too large".into());
+        }
+    }
+
+    let list: Value = serde_json::from_slice(&body)?;
+    match list.get("data") {
+        Some(Value::Array(models)) => Ok(models.clone()),
+        _ => Err("no `data` array in model list".into()),
+    }
+}
+
+// model entries for a backend that can't be asked, from the models it registered with
+pub fn declared(models: &[String]) -> Vec<Value> {
+    models
+        .iter()
+        .map(|id| {
+            serde_json::json!({
+                "id": id,
+                "object": "model",
+                "created": 0,
+                "owned_by": "llamaedge",
+            })
+        })
+        .collect()
+}
+
+// one openai-style model list out of every backend's, each model listed once with the
+// backends that serve it under `backends`
+pub fn merge(lists: Vec<(String, Vec<Value>)>) -> Value {
+    let mut merged: BTreeMap<String, Value> = BTreeMap::new();
+    for (service, models) in lists {
+        for model in models {
+            let Some(id) = model.get("id").and_then(|id| id.as_str()) else {
+                continue;
+            };
+            let entry = merged.entry(id.to_string()).or_insert_with(|| {
+                let mut entry = model.clone();
+                entry["backends"] = Value::Array(Vec::new());
+                entry
+            });
+            if let Some(backends) = entry["backends"].as_array_mut()
+                && !backends.iter().any(|b| b.as_str() == Some(&service))
+            {
+                backends.push(Value::String(service.clone()));
+            }
+        }
+    }
+    serde_json::json!({
+        "object": "list",
+        "data": merged.into_values().collect::<Vec<_>>(),
+    })
+}
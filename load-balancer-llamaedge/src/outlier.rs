@@ -0,0 +1,202 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct OutlierSettings {
+    // consecutive failed requests before a service is ejected, at least 1
+    pub consecutive_errors: u32,
+    // first ejection lasts this long, every repeated ejection doubles it
+    pub base_ejection: Duration,
+    pub max_ejection: Duration,
+    // never eject more than this share of all services at once
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierSettings {
+    fn default() -> Self {
+        Self {
+            consecutive_errors: 5,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OutlierState {
+    consecutive_errors: u32,
+    // ejections in a row without a quiet period in between, drives the backoff
+    ejections: u32,
+    ejected_until: Option<Instant>,
+    last_error: Option<String>,
+}
+
+// outlier state as listed by `GET /api/services`
+#[derive(Debug, Clone, Serialize)]
+pub struct OutlierStatus {
+    pub ejected: bool,
+    pub ejected_remaining_secs: u64,
+    pub consecutive_errors: u32,
+    pub ejections: u32,
+    pub last_error: Option<String>,
+}
+
+impl OutlierState {
+    pub fn is_ejected(&self) -> bool {
+        self.ejected_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    pub fn record_success(&mut self, settings: &OutlierSettings) {
+        self.consecutive_errors = 0;
+        // forget earlier ejections once the service stayed well for a full max period
+        if let Some(until) = self.ejected_until
+            && until.elapsed() > settings.max_ejection
+        {
+            self.ejections = 0;
+            self.ejected_until = None;
+        }
+    }
+
+    // records a failed request, returns the ejection time if this failure ejected the service
+    //
+    // `can_eject` is false when ejecting would exceed the allowed share of ejected services
+    pub fn record_failure(
+        &mut self,
+        error: String,
+        settings: &OutlierSettings,
+        can_eject: bool,
+    ) -> Option<Duration> {
+        self.consecutive_errors += 1;
+        self.last_error = Some(error);
+
+        if self.consecutive_errors < settings.consecutive_errors || self.is_ejected() || !can_eject
+        {
+            return None;
+        }
+
+        let factor = 2u32.saturating_pow(self.ejections.min(16));
+        let duration = settings
+            .base_ejection
+            .saturating_mul(factor)
+            .min(settings.max_ejection);
+        self.ejections += 1;
+        self.consecutive_errors = 0;
+        self.ejected_until = Some(Instant::now() + duration);
+        Some(duration)
+    }
+
+    pub fn status(&self) -> OutlierStatus {
+        let remaining = self
+            .ejected_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .unwrap_or_default();
+        OutlierStatus {
+            ejected: self.is_ejected(),
+            ejected_remaining_secs: remaining.as_secs(),
+            consecutive_errors: self.consecutive_errors,
+            ejections: self.ejections,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> OutlierSettings {
+        OutlierSettings {
+            consecutive_errors: 2,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(100),
+            max_ejection_percent: 50,
+        }
+    }
+
+    // fails the service until it is ejected, returns the ejection time
+    fn eject(state: &mut OutlierState, settings: &OutlierSettings) -> Duration {
+        assert_eq!(
+            state.record_failure("http 502".to_string(), settings, true),
+            None
+        );
+        let ejected = state.record_failure("http 502".to_string(), settings, true);
+        // the ejection is over straight away so the next one can start
+        state.ejected_until = Some(Instant::now());
+        ejected.unwrap()
+    }
+
+    #[test]
+    fn ejected_after_consecutive_errors() {
+        let settings = settings();
+        let mut state = OutlierState::default();
+        assert_eq!(state.record_failure("a".to_string(), &settings, true), None);
+        state.record_success(&settings);
+        assert_eq!(state.record_failure("b".to_string(), &settings, true), None);
+        assert_eq!(
+            state.record_failure("c".to_string(), &settings, true),
+            Some(Duration::from_secs(30))
+        );
+        assert!(state.is_ejected());
+        let status = state.status();
+        assert_eq!(status.ejections, 1);
+        assert_eq!(status.consecutive_errors, 0);
+        assert_eq!(status.last_error.as_deref(), Some("c"));
+        // failures while ejected don't extend the ejection
+        assert_eq!(state.record_failure("d".to_string(), &settings, true), None);
+        assert_eq!(state.record_failure("e".to_string(), &settings, true), None);
+    }
+
+    #[test]
+    fn not_ejected_beyond_the_allowed_share() {
+        let settings = settings();
+        let mut state = OutlierState::default();
+        assert_eq!(
+            state.record_failure("a".to_string(), &settings, false),
+            None
+        );
+        assert_eq!(
+            state.record_failure("b".to_string(), &settings, false),
+            None
+        );
+        assert!(!state.is_ejected());
+        assert_eq!(state.status().ejections, 0);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let settings = settings();
+        let mut state = OutlierState::default();
+        let ejections: Vec<u64> = (0..5)
+            .map(|_| eject(&mut state, &settings).as_secs())
+            .collect();
+        assert_eq!(ejections, vec![30, 60, 100, 100, 100]);
+
+        // far more ejections than the factor can express still stay at the cap
+        state.ejections = 40;
+        assert_eq!(eject(&mut state, &settings), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn backoff_resets_after_a_quiet_period() {
+        let settings = OutlierSettings {
+            max_ejection: Duration::from_millis(5),
+            base_ejection: Duration::from_millis(1),
+            ..settings()
+        };
+        let mut state = OutlierState::default();
+        eject(&mut state, &settings);
+        eject(&mut state, &settings);
+        assert_eq!(state.status().ejections, 2);
+
+        // a success right after the ejection keeps the backoff
+        state.record_success(&settings);
+        assert_eq!(state.status().ejections, 2);
+
+        std::thread::sleep(Duration::from_millis(10));
+        state.record_success(&settings);
+        assert_eq!(state.status().ejections, 0);
+        assert_eq!(eject(&mut state, &settings), Duration::from_millis(1));
+    }
+}
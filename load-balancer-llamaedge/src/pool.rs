@@ -0,0 +1,94 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone)]
+pub struct PoolSettings {
+    // idle connections kept per service, 0 disables pooling
+    pub max_idle: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_idle: 8,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+struct IdleConnection {
+    stream: TcpStream,
+    since: Instant,
+}
+
+// idle keep-alive connections to a single backend
+pub struct ConnectionPool {
+    settings: PoolSettings,
+    idle: Mutex<Vec<IdleConnection>>,
+}
+
+// a closed or misbehaving idle connection shows up as readable (eof or stray bytes)
+fn is_alive(stream: &TcpStream) -> bool {
+    let mut probe = [0u8; 1];
+    matches!(stream.try_read(&mut probe), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+impl ConnectionPool {
+    pub fn new(settings: PoolSettings) -> Self {
+        Self {
+            settings,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    // most recently used live idle connection, if any
+    fn take_idle(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(conn) = idle.pop() {
+            if conn.since.elapsed() < self.settings.idle_timeout && is_alive(&conn.stream) {
+                return Some(conn.stream);
+            }
+        }
+        None
+    }
+
+    // reuse an idle connection or open a new one, the flag tells whether it was reused
+    pub async fn checkout(&self, address: &str) -> std::io::Result<(TcpStream, bool)> {
+        if let Some(stream) = self.take_idle() {
+            return Ok((stream, true));
+        }
+        let stream = TcpStream::connect(address).await?;
+        Ok((stream, false))
+    }
+
+    // hand a connection back after a complete response
+    pub fn checkin(&self, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.settings.max_idle {
+            idle.push(IdleConnection {
+                stream,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    // drop expired or closed idle connections, returns how many were dropped
+    pub fn sweep(&self) -> usize {
+        let mut idle = self.idle.lock().unwrap();
+        let before = idle.len();
+        idle.retain(|conn| {
+            conn.since.elapsed() < self.settings.idle_timeout && is_alive(&conn.stream)
+        });
+        before - idle.len()
+    }
+
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
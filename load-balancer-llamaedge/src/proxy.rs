@@ -0,0 +1,404 @@
+use crate::http::{
+    self, BodyDecoder, BodyKind, Forwarding, HttpRequest, ParseError, RequestLimits,
+};
+use crate::timeouts::{RequestTimeouts, TimeoutKind};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// largest non-streaming response body kept around to read its `usage` from
+const MAX_USAGE_BODY: usize = 1024 * 1024;
+
+// token counts as reported in the `usage` object of openai-style responses
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        // the counts come from the backend, they may be anything
+        self.total_tokens
+            .max(self.prompt_tokens.saturating_add(self.completion_tokens))
+    }
+}
+
+// the `usage` object of a json response body or sse event, if it has one
+fn parse_usage(json: &[u8]) -> Option<Usage> {
+    let value: serde_json::Value = serde_json::from_slice(json).ok()?;
+    serde_json::from_value(value.get("usage")?.clone()).ok()
+}
+
+#[derive(Debug, Default)]
+pub struct RelayStats {
+    pub status: u16,
+    // response is a server-sent event stream
+    pub streaming: bool,
+    pub body_bytes: usize,
+    // number of sse `data:` events relayed
+    pub events: usize,
+    pub time_to_first_byte: Duration,
+    pub time_to_first_token: Option<Duration>,
+    pub total: Duration,
+    // client hung up before the response was complete
+    pub client_closed: bool,
+    // the client stopped reading and was cut off after this timeout
+    pub client_timeout: Option<TimeoutKind>,
+    // client connection stays open for another request
+    pub keep_alive: bool,
+    // backend connection ended cleanly and can go back to the pool
+    pub backend_reusable: bool,
+    // token usage reported by the backend, `None` if it didn't report any
+    pub usage: Option<Usage>,
+}
+
+impl RelayStats {
+    // tokens the response used - streams without a `usage` chunk are counted as
+    // one completion token per event, leaving out the closing `[DONE]`
+    pub fn tokens_used(&self) -> u64 {
+        match &self.usage {
+            Some(usage) => usage.total(),
+            None if self.streaming => self.events.saturating_sub(1) as u64,
+            None => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RelayError {
+    pub error: ParseError,
+    // whether anything was already written to the client
+    pub response_started: bool,
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.response_started {
+            write!(f, "backend failed mid-response: {}", self.error)
+        } else {
+            write!(f, "backend failed before responding: {}", self.error)
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+// counts sse `data:` lines across arbitrarily split body pieces and keeps the
+// `usage` of the last event that carried one (usually the final chunk)
+struct SseScanner {
+    partial: Vec<u8>,
+    // longest line kept, the rest of a longer one is skipped up to its newline
+    max_line: usize,
+    skipping: bool,
+    events: usize,
+    usage: Option<Usage>,
+}
+
+impl SseScanner {
+    fn new(max_line: usize) -> Self {
+        Self {
+            partial: Vec::new(),
+            max_line,
+            skipping: false,
+            events: 0,
+            usage: None,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        self.partial.extend_from_slice(data);
+        while let Some(pos) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=pos).collect();
+            if self.skipping {
+                // the end of an oversized line
+                self.skipping = false;
+                continue;
+            }
+            if let Some(event) = line.strip_prefix(b"data:") {
+                self.events += 1;
+                if event.windows(7).any(|w| w == b"\"usage\"")
+                    && let Some(usage) = parse_usage(event)
+                {
+                    self.usage = Some(usage);
+                }
+            }
+        }
+        // a backend streaming without newlines must not grow the buffer without bound -
+        // an oversized event still counts, its usage is lost
+        if self.partial.len() > self.max_line {
+            if !self.skipping && self.partial.starts_with(b"data:") {
+                self.events += 1;
+            }
+            self.skipping = true;
+            self.partial.clear();
+        }
+    }
+}
+
+// resolves once the client has hung up (`true`) or has sent more data (`false`)
+async fn client_hung_up(client: &TcpStream) -> bool {
+    let mut probe = [0u8; 1];
+    matches!(client.peek(&mut probe).await, Ok(0) | Err(_))
+}
+
+// writes to the client within the idle timeout and the request's deadline - fails with
+// the timeout if the client stopped reading, or `None` if it is gone
+async fn write_flush(
+    client: &mut TcpStream,
+    data: &[u8],
+    timeouts: &RequestTimeouts,
+) -> Result<(), Option<TimeoutKind>> {
+    let written = async {
+        client.write_all(data).await?;
+        client.flush().await
+    };
+    match timeouts
+        .run(timeouts.idle, TimeoutKind::ClientWrite, written)
+        .await
+    {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(_)) => Err(None),
+        Err(kind) => Err(Some(kind)),
+    }
+}
+
+// sends the request to the backend and relays its response, see `relay_response`
+pub async fn forward(
+    backend: &mut TcpStream,
+    client: &mut TcpStream,
+    request: &HttpRequest,
+    stream_requested: bool,
+    client_keep_alive: bool,
+    forwarding: &Forwarding<'_>,
+    limits: &RequestLimits,
+) -> Result<RelayStats, RelayError> {
+    let sent = async {
+        backend.write_all(&request.head_bytes(forwarding)).await?;
+        backend.write_all(&request.body).await?;
+        backend.flush().await
+    };
+    let not_started = |error| RelayError {
+        error,
+        response_started: false,
+    };
+    forwarding
+        .timeouts
+        .run(None, TimeoutKind::Total, sent)
+        .await
+        .map_err(|kind| not_started(ParseError::Timeout(kind)))?
+        .map_err(|e| not_started(ParseError::Io(e)))?;
+
+    relay_response(
+        backend,
+        client,
+        &request.method,
+        stream_requested,
+        client_keep_alive,
+        limits,
+        forwarding,
+    )
+    .await
+}
+
+// relays one backend response to the client, piece by piece as it arrives
+//
+// each piece is flushed straight away so sse events reach the client without delay.
+// if the client goes away mid-response this returns early with `client_closed` set -
+// dropping the backend connection afterwards is what makes the backend stop generating
+async fn relay_response(
+    backend: &mut TcpStream,
+    client: &mut TcpStream,
+    request_method: &str,
+    stream_requested: bool,
+    client_keep_alive: bool,
+    limits: &RequestLimits,
+    forwarding: &Forwarding<'_>,
+) -> Result<RelayStats, RelayError> {
+    let started = Instant::now();
+    let mut buf = Vec::new();
+    let timeouts = forwarding.timeouts;
+
+    let head = timeouts
+        .run(
+            timeouts.first_byte,
+            TimeoutKind::FirstByte,
+            http::read_response_head(backend, &mut buf, limits),
+        )
+        .await
+        .map_err(ParseError::Timeout)
+        .and_then(|head| head)
+        .map_err(|error| RelayError {
+            error,
+            response_started: false,
+        })?;
+
+    let mut stats = RelayStats {
+        status: head.status,
+        streaming: stream_requested || head.is_event_stream(),
+        time_to_first_byte: started.elapsed(),
+        ..Default::default()
+    };
+
+    let kind = head.body_kind(request_method);
+    let chunked = kind == BodyKind::Chunked;
+    // a close-delimited body can only be passed on by closing the client connection too
+    let keep_alive = client_keep_alive && kind != BodyKind::UntilClose;
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let head_bytes = head.head_bytes(connection, forwarding.response_headers);
+    if let Err(timeout) = write_flush(client, &head_bytes, timeouts).await {
+        stats.client_closed = true;
+        stats.client_timeout = timeout;
+        stats.total = started.elapsed();
+        return Ok(stats);
+    }
+
+    let mut decoder = BodyDecoder::new(kind);
+    let mut scanner = SseScanner::new(limits.max_header_bytes);
+    // non-streaming json bodies are kept to read the token usage once complete
+    let mut body = Vec::new();
+    let mut keep_body = !stats.streaming;
+    let mut watch_client = true;
+
+    loop {
+        let piece = tokio::select! {
+            piece = timeouts.run(timeouts.idle, TimeoutKind::Idle, decoder.next(backend, &mut buf)) => piece
+                .map_err(ParseError::Timeout)
+                .and_then(|piece| piece)
+                .map_err(|error| RelayError {
+                    error,
+                    response_started: true,
+                })?,
+            hung_up = client_hung_up(client), if watch_client => {
+                if hung_up {
+                    stats.client_closed = true;
+                    break;
+                }
+                // client already sent its next request, stop watching
+                watch_client = false;
+                continue;
+            }
+        };
+
+        let Some(data) = piece else {
+            if chunked && let Err(timeout) = write_flush(client, b"0\r\n\r\n", timeouts).await {
+                stats.client_closed = true;
+                stats.client_timeout = timeout;
+            }
+            break;
+        };
+
+        stats.body_bytes += data.len();
+        if stats.streaming {
+            scanner.feed(&data);
+            if stats.time_to_first_token.is_none() && scanner.events > 0 {
+                stats.time_to_first_token = Some(started.elapsed());
+            }
+        } else if keep_body {
+            keep_body = body.len() + data.len() <= MAX_USAGE_BODY;
+            if keep_body {
+                body.extend_from_slice(&data);
+            }
+        }
+
+        let written = if chunked {
+            let mut framed = format!("{:x}\r\n", data.len()).into_bytes();
+            framed.extend_from_slice(&data);
+            framed.extend_from_slice(b"\r\n");
+            write_flush(client, &framed, timeouts).await
+        } else {
+            write_flush(client, &data, timeouts).await
+        };
+        if let Err(timeout) = written {
+            stats.client_closed = true;
+            stats.client_timeout = timeout;
+            break;
+        }
+    }
+
+    stats.events = scanner.events;
+    stats.usage = if stats.streaming {
+        scanner.usage
+    } else if keep_body && !stats.client_closed {
+        parse_usage(&body)
+    } else {
+        None
+    };
+    stats.keep_alive = keep_alive && !stats.client_closed;
+    // anything left in the buffer would be read as the start of the next response
+    stats.backend_reusable = !stats.client_closed
+        && kind != BodyKind::UntilClose
+        && buf.is_empty()
+        && head.allows_reuse();
+    stats.total = started.elapsed();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_events_split_across_pieces() {
+        let mut scanner = SseScanner::new(1024);
+        scanner.feed(b"data: {\"choices\":[]}\n\nda");
+        assert_eq!(scanner.events, 1);
+        scanner.feed(b"ta: {\"choices\"");
+        assert_eq!(scanner.events, 1);
+        scanner.feed(b":[]}\n\n: keep-alive comment\n\ndata: [DONE]\n\n");
+        assert_eq!(scanner.events, 3);
+        assert!(scanner.usage.is_none());
+    }
+
+    #[test]
+    fn keeps_usage_of_the_last_event() {
+        let mut scanner = SseScanner::new(1024);
+        scanner.feed(b"data: {\"usage\":{\"prompt_tokens\":1,\"completion_tokens\":2}}\n\n");
+        scanner.feed(b"data: {\"usage\":{\"prompt_tokens\":3,");
+        scanner.feed(b"\"completion_tokens\":4,\"total_tokens\":7}}\r\n\r\ndata: [DONE]\n\n");
+        let usage = scanner.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (3, 4));
+        assert_eq!(usage.total(), 7);
+        assert_eq!(scanner.events, 3);
+    }
+
+    #[test]
+    fn streams_without_usage_count_events() {
+        let stats = RelayStats {
+            streaming: true,
+            events: 4,
+            ..RelayStats::default()
+        };
+        assert_eq!(stats.tokens_used(), 3);
+    }
+
+    #[test]
+    fn oversized_lines_are_dropped() {
+        let mut scanner = SseScanner::new(16);
+        scanner.feed(b"data: {\"choices\":[");
+        scanner.feed(&[b'x'; 64]);
+        assert!(scanner.partial.is_empty());
+        assert_eq!(scanner.events, 1);
+        // the rest of the oversized event is skipped, not counted again
+        scanner.feed(&[b'x'; 64]);
+        scanner.feed(b"]}\n\ndata: [DONE]\n\n");
+        assert_eq!(scanner.events, 2);
+        assert!(scanner.partial.is_empty());
+    }
+
+    #[test]
+    fn usage_totals_saturate() {
+        let usage = Usage {
+            prompt_tokens: u64::MAX,
+            completion_tokens: 2,
+            total_tokens: 0,
+        };
+        assert_eq!(usage.total(), u64::MAX);
+    }
+}
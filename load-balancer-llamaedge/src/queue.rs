@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
+
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    // requests allowed to wait for a free backend slot at once, more get `429`
+    pub max_waiting: usize,
+    // a request that waited this long without a free slot gets `503`
+    pub timeout: Duration,
+    // sent as `Retry-After` with either rejection
+    pub retry_after: Duration,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            max_waiting: 100,
+            timeout: Duration::from_secs(30),
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+// why a request could not get a backend slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueRejection {
+    Full,
+    TimedOut,
+}
+
+// requests waiting for a backend below its `max_concurrency`
+#[derive(Default)]
+pub struct WaitQueue {
+    waiting: AtomicUsize,
+    released: Notify,
+}
+
+// a place in the queue, given up when dropped
+pub struct QueueTicket<'a>(&'a WaitQueue);
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl WaitQueue {
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    // `None` when the queue is already full
+    pub fn join(&self, settings: &QueueSettings) -> Option<QueueTicket<'_>> {
+        let previous = self.waiting.fetch_add(1, Ordering::Relaxed);
+        let ticket = QueueTicket(self);
+        (previous < settings.max_waiting).then_some(ticket)
+    }
+
+    // resolves on the next `notify`, take it before checking for capacity so a slot
+    // freed in between is not missed
+    pub fn released(&self) -> Notified<'_> {
+        self.released.notified()
+    }
+
+    // a slot was freed or a backend was added - let every waiter look again
+    pub fn notify(&self) {
+        self.released.notify_waiters();
+    }
+}
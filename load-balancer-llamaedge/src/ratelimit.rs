@@ -0,0 +1,317 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// limits applied to one api key (tenant), zero means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantLimits {
+    #[serde(default)]
+    pub requests_per_minute: u32,
+    #[serde(default)]
+    pub tokens_per_minute: u64,
+    // tokens per calendar month (utc), checked against the usage ledger
+    #[serde(default)]
+    pub monthly_tokens: u64,
+}
+
+// token bucket that refills its full capacity once per minute
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64) -> Self {
+        Self {
+            capacity,
+            available: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    // time until `amount` is available again
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount - self.available;
+        if missing <= 0.0 || self.capacity <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing * 60.0 / self.capacity)
+    }
+
+    // time until the bucket is full again
+    fn reset(&self) -> Duration {
+        self.wait_for(self.capacity)
+    }
+}
+
+struct TenantBuckets {
+    limits: TenantLimits,
+    requests: Bucket,
+    tokens: Bucket,
+}
+
+impl TenantBuckets {
+    fn new(limits: TenantLimits) -> Self {
+        Self {
+            limits,
+            requests: Bucket::new(limits.requests_per_minute as f64),
+            tokens: Bucket::new(limits.tokens_per_minute as f64),
+        }
+    }
+}
+
+// where a tenant stands against its limits, sent back as `x-ratelimit-*` headers
+#[derive(Debug, Clone)]
+pub struct RateLimitStatus {
+    limits: TenantLimits,
+    remaining_requests: u64,
+    remaining_tokens: u64,
+    reset_requests: Duration,
+    reset_tokens: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimited {
+    // "requests" or "tokens"
+    pub limit: &'static str,
+    pub retry_after: Duration,
+    pub status: RateLimitStatus,
+}
+
+// `20ms`, `1s`, `6m0s` - the duration format of openai's reset headers
+fn format_reset(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        return format!("{}ms", duration.as_millis());
+    }
+    let secs = duration.as_secs_f64().ceil() as u64;
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+impl RateLimitStatus {
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if self.limits.requests_per_minute > 0 {
+            headers.push((
+                "x-ratelimit-limit-requests",
+                self.limits.requests_per_minute.to_string(),
+            ));
+            headers.push((
+                "x-ratelimit-remaining-requests",
+                self.remaining_requests.to_string(),
+            ));
+            headers.push((
+                "x-ratelimit-reset-requests",
+                format_reset(self.reset_requests),
+            ));
+        }
+        if self.limits.tokens_per_minute > 0 {
+            headers.push((
+                "x-ratelimit-limit-tokens",
+                self.limits.tokens_per_minute.to_string(),
+            ));
+            headers.push((
+                "x-ratelimit-remaining-tokens",
+                self.remaining_tokens.to_string(),
+            ));
+            headers.push(("x-ratelimit-reset-tokens", format_reset(self.reset_tokens)));
+        }
+        headers
+    }
+}
+
+// request and token buckets per tenant
+#[derive(Default)]
+pub struct RateLimiter {
+    default: TenantLimits,
+    // limits set for single tenants through `/api/keys`, everyone else gets the default
+    overrides: Mutex<HashMap<String, TenantLimits>>,
+    buckets: Mutex<HashMap<String, TenantBuckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(default: TenantLimits) -> Self {
+        Self {
+            default,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_limits(&self, tenant: &str, limits: TenantLimits) {
+        self.overrides
+            .lock()
+            .unwrap()
+            .insert(tenant.to_string(), limits);
+    }
+
+    pub fn clear_limits(&self, tenant: &str) {
+        self.overrides.lock().unwrap().remove(tenant);
+        self.buckets.lock().unwrap().remove(tenant);
+    }
+
+    pub fn limits_for(&self, tenant: &str) -> TenantLimits {
+        self.overrides
+            .lock()
+            .unwrap()
+            .get(tenant)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    fn status(buckets: &TenantBuckets) -> RateLimitStatus {
+        RateLimitStatus {
+            limits: buckets.limits,
+            remaining_requests: buckets.requests.available.max(0.0) as u64,
+            remaining_tokens: buckets.tokens.available.max(0.0) as u64,
+            reset_requests: buckets.requests.reset(),
+            reset_tokens: buckets.tokens.reset(),
+        }
+    }
+
+    // takes one request from the tenant's bucket - token usage is only known once the
+    // response is in, so a tenant is stopped once earlier requests used up its tokens
+    pub fn acquire(&self, tenant: &str) -> Result<RateLimitStatus, RateLimited> {
+        let limits = self.limits_for(tenant);
+        let mut all = self.buckets.lock().unwrap();
+        let buckets = all
+            .entry(tenant.to_string())
+            .or_insert_with(|| TenantBuckets::new(limits));
+        if buckets.limits != limits {
+            *buckets = TenantBuckets::new(limits);
+        }
+        buckets.requests.refill();
+        buckets.tokens.refill();
+
+        if limits.requests_per_minute > 0 && buckets.requests.available < 1.0 {
+            return Err(RateLimited {
+                limit: "requests",
+                retry_after: buckets.requests.wait_for(1.0),
+                status: Self::status(buckets),
+            });
+        }
+        if limits.tokens_per_minute > 0 && buckets.tokens.available < 1.0 {
+            return Err(RateLimited {
+                limit: "tokens",
+                retry_after: buckets.tokens.wait_for(1.0),
+                status: Self::status(buckets),
+            });
+        }
+
+        if limits.requests_per_minute > 0 {
+            buckets.requests.available -= 1.0;
+        }
+        Ok(Self::status(buckets))
+    }
+
+    // charges the tokens a finished request used, the bucket may go below zero
+    pub fn consume_tokens(&self, tenant: &str, tokens: u64) {
+        let mut all = self.buckets.lock().unwrap();
+        if let Some(buckets) = all.get_mut(tenant)
+            && buckets.limits.tokens_per_minute > 0
+        {
+            buckets.tokens.refill();
+            buckets.tokens.available -= tokens as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(requests_per_minute: u32, tokens_per_minute: u64) -> TenantLimits {
+        TenantLimits {
+            requests_per_minute,
+            tokens_per_minute,
+            monthly_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn request_bucket_runs_out() {
+        let limiter = RateLimiter::new(limits(3, 0));
+        for remaining in [2, 1, 0] {
+            let status = limiter.acquire("team-a").unwrap();
+            assert_eq!(status.remaining_requests, remaining);
+        }
+        let limited = limiter.acquire("team-a").unwrap_err();
+        assert_eq!(limited.limit, "requests");
+        // one request comes back every 20 seconds
+        assert!(limited.retry_after > Duration::from_secs(19));
+        assert!(limited.retry_after <= Duration::from_secs(20));
+        // other tenants have their own buckets
+        assert!(limiter.acquire("team-b").is_ok());
+    }
+
+    #[test]
+    fn tokens_are_charged_after_the_response() {
+        let limiter = RateLimiter::new(limits(0, 100));
+        assert!(limiter.acquire("team-a").is_ok());
+        limiter.consume_tokens("team-a", 150);
+        let limited = limiter.acquire("team-a").unwrap_err();
+        assert_eq!(limited.limit, "tokens");
+        // 51 tokens short at 100 per minute
+        assert!(limited.retry_after > Duration::from_secs(30));
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let limiter = RateLimiter::new(TenantLimits::default());
+        for _ in 0..1000 {
+            limiter.consume_tokens("team-a", 1_000_000);
+            let status = limiter.acquire("team-a").unwrap();
+            assert!(status.headers().is_empty());
+        }
+    }
+
+    #[test]
+    fn overrides_replace_the_default() {
+        let limiter = RateLimiter::new(limits(1, 0));
+        limiter.set_limits("batch", limits(2, 0));
+        assert_eq!(limiter.limits_for("batch"), limits(2, 0));
+        assert!(limiter.acquire("batch").is_ok());
+        assert!(limiter.acquire("batch").is_ok());
+        assert!(limiter.acquire("batch").is_err());
+
+        limiter.clear_limits("batch");
+        assert_eq!(limiter.limits_for("batch"), limits(1, 0));
+        assert!(limiter.acquire("batch").is_ok());
+        assert!(limiter.acquire("batch").is_err());
+    }
+
+    #[test]
+    fn reset_headers() {
+        assert_eq!(format_reset(Duration::from_millis(20)), "20ms");
+        assert_eq!(format_reset(Duration::from_millis(1500)), "2s");
+        assert_eq!(format_reset(Duration::from_secs(360)), "6m0s");
+
+        let limiter = RateLimiter::new(limits(60, 1000));
+        let headers = limiter.acquire("team-a").unwrap().headers();
+        let names: Vec<_> = headers.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            [
+                "x-ratelimit-limit-requests",
+                "x-ratelimit-remaining-requests",
+                "x-ratelimit-reset-requests",
+                "x-ratelimit-limit-tokens",
+                "x-ratelimit-remaining-tokens",
+                "x-ratelimit-reset-tokens",
+            ]
+        );
+        assert_eq!(headers[1].1, "59");
+    }
+}
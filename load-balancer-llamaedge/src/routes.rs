@@ -0,0 +1,66 @@
+// which requests are balanced and which services may serve them
+//
+// a route names the pool of services it is sent to, services join pools through the
+// `pools` field of their registration - services without pools serve every route whose
+// pool has no members of its own, so a plain llama-api-server keeps serving everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub pool: &'static str,
+    // the body is json with `model` and `stream` fields, anything else (multipart
+    // uploads, no body) is passed on without looking at it
+    pub json_body: bool,
+    // answered by the balancer from every backend's answer instead of a single backend
+    pub aggregate: bool,
+}
+
+const fn route(
+    method: &'static str,
+    path: &'static str,
+    pool: &'static str,
+    json_body: bool,
+) -> Route {
+    Route {
+        method,
+        path,
+        pool,
+        json_body,
+        aggregate: false,
+    }
+}
+
+// the openai-compatible endpoints of llama-api-server, whisper-api-server and sd-api-server -
+// the `/v1/files` family is left out since uploaded files live on a single backend
+pub const ROUTES: &[Route] = &[
+    route("POST", "/v1/chat/completions", "chat", true),
+    route("POST", "/v1/completions", "chat", true),
+    route("POST", "/v1/embeddings", "embeddings", true),
+    Route {
+        method: "GET",
+        path: "/v1/models",
+        pool: "chat",
+        json_body: false,
+        aggregate: true,
+    },
+    route("GET", "/v1/info", "chat", false),
+    route("POST", "/v1/audio/transcriptions", "audio", false),
+    route("POST", "/v1/audio/translations", "audio", false),
+    route("POST", "/v1/audio/speech", "audio", true),
+    route("POST", "/v1/images/generations", "images", true),
+    route("POST", "/v1/images/edits", "images", false),
+];
+
+// the route for a request, the query string is not part of the match
+pub fn find(method: &str, path: &str) -> Option<&'static Route> {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    ROUTES
+        .iter()
+        .find(|route| route.method == method && route.path == path)
+}
+
+// whether any route is served under `path` with another method, for `405` vs `404`
+pub fn path_known(path: &str) -> bool {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    ROUTES.iter().any(|route| route.path == path)
+}
@@ -0,0 +1,468 @@
+use crate::Service;
+use crate::latency::LatencyStatus;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// a service that may take the request, with its current load
+pub struct Candidate<'a> {
+    pub service: &'a Service,
+    // requests currently being forwarded to the service
+    pub in_flight: usize,
+    // `None` until the service answered at least once
+    pub latency: Option<LatencyStatus>,
+}
+
+pub trait BalancingStrategy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // index of the chosen candidate, `candidates` is never empty
+    fn select(&self, candidates: &[Candidate]) -> usize;
+}
+
+pub const STRATEGY_NAMES: [&str; 5] = [
+    "weighted_random",
+    "weighted_round_robin",
+    "least_outstanding",
+    "power_of_two",
+    "peak_ewma",
+];
+
+pub fn from_name(name: &str) -> Option<Arc<dyn BalancingStrategy>> {
+    match name {
+        "weighted_random" => Some(Arc::new(WeightedRandom)),
+        "weighted_round_robin" => Some(Arc::new(WeightedRoundRobin::default())),
+        "least_outstanding" => Some(Arc::new(LeastOutstanding)),
+        "power_of_two" => Some(Arc::new(PowerOfTwoChoices)),
+        "peak_ewma" => Some(Arc::new(PeakEwmaLatency)),
+        _ => None,
+    }
+}
+
+// weights used for selection - if every weight is zero all services count equally
+fn effective_weights(candidates: &[Candidate]) -> Vec<u32> {
+    if candidates.iter().all(|c| c.service.weight == 0) {
+        vec![1; candidates.len()]
+    } else {
+        candidates.iter().map(|c| c.service.weight).collect()
+    }
+}
+
+// weighted random draw over `weights`, skipping indices in `skip`
+//
+// summed in u64, a few large u32 weights overflow a u32 total
+fn weighted_draw(weights: &[u32], skip: Option<usize>) -> Option<usize> {
+    let total_weight: u64 = weights
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| Some(*i) != skip)
+        .map(|(_, w)| u64::from(*w))
+        .sum();
+    if total_weight == 0 {
+        return None;
+    }
+
+    let mut choice = rand::rng().random_range(0..total_weight);
+    for (i, weight) in weights.iter().enumerate() {
+        if Some(i) == skip {
+            continue;
+        }
+        let weight = u64::from(*weight);
+        if choice < weight {
+            return Some(i);
+        }
+        choice -= weight;
+    }
+    None
+}
+
+// pick a service at random, proportional to its weight
+pub struct WeightedRandom;
+
+impl BalancingStrategy for WeightedRandom {
+    fn name(&self) -> &'static str {
+        "weighted_random"
+    }
+
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        let total_weight: u64 = candidates.iter().map(|c| u64::from(c.service.weight)).sum();
+        if total_weight == 0 {
+            debug!(
+                "all services have zero weight, selecting first service: {}",
+                candidates[0].service.name
+            );
+            return 0;
+        }
+
+        let mut rng = rand::rng();
+        let mut choice = rng.random_range(0..total_weight);
+        let original_choice = choice;
+
+        for (i, candidate) in candidates.iter().enumerate() {
+            let service = candidate.service;
+            let weight = u64::from(service.weight);
+            if choice < weight {
+                debug!(
+                    "selected service '{}' (choice: {}/{}, weight: {})",
+                    service.name, original_choice, total_weight, service.weight
+                );
+                return i;
+            }
+            // choice -= service.weight; // improved to :
+            choice = choice.saturating_sub(weight);
+        }
+
+        // fallback to first service (should be rare)
+        warn!(
+            "none of the services got selected, falling back to the first service: {}",
+            candidates[0].service.name
+        );
+        0
+    }
+}
+
+// nginx-style smooth weighted round robin - weights are honoured without bursts
+// of consecutive picks of the heaviest service
+#[derive(Default)]
+pub struct WeightedRoundRobin {
+    // running "current weight" per service name
+    current: Mutex<HashMap<String, i64>>,
+}
+
+impl BalancingStrategy for WeightedRoundRobin {
+    fn name(&self) -> &'static str {
+        "weighted_round_robin"
+    }
+
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        let weights = effective_weights(candidates);
+        let total: i64 = weights.iter().map(|w| *w as i64).sum();
+        let mut current = self.current.lock().unwrap();
+
+        // services that left the registry don't need their counters anymore
+        current.retain(|name, _| candidates.iter().any(|c| &c.service.name == name));
+
+        let mut best = 0;
+        let mut best_value = i64::MIN;
+        for (i, candidate) in candidates.iter().enumerate() {
+            let value = current.entry(candidate.service.name.clone()).or_insert(0);
+            *value += weights[i] as i64;
+            if *value > best_value {
+                best = i;
+                best_value = *value;
+            }
+        }
+        if let Some(value) = current.get_mut(&candidates[best].service.name) {
+            *value -= total;
+        }
+
+        debug!(
+            "selected service '{}' (round robin, weight: {})",
+            candidates[best].service.name, candidates[best].service.weight
+        );
+        best
+    }
+}
+
+// pick the service with the fewest in-flight requests relative to its weight
+pub struct LeastOutstanding;
+
+impl BalancingStrategy for LeastOutstanding {
+    fn name(&self) -> &'static str {
+        "least_outstanding"
+    }
+
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        let weights = effective_weights(candidates);
+        // compare in_flight / weight without floats: a/wa < b/wb <=> a*wb < b*wa
+        let mut best: Vec<usize> = Vec::new();
+        for (i, candidate) in candidates.iter().enumerate() {
+            if weights[i] == 0 {
+                continue;
+            }
+            let Some(&first) = best.first() else {
+                best.push(i);
+                continue;
+            };
+            let lhs = candidate.in_flight as u64 * weights[first] as u64;
+            let rhs = candidates[first].in_flight as u64 * weights[i] as u64;
+            if lhs < rhs {
+                best.clear();
+                best.push(i);
+            } else if lhs == rhs {
+                best.push(i);
+            }
+        }
+
+        // ties are broken at random so equal services share the load
+        let chosen = best[rand::rng().random_range(0..best.len())];
+        debug!(
+            "selected service '{}' (least outstanding: {} in flight, weight: {})",
+            candidates[chosen].service.name,
+            candidates[chosen].in_flight,
+            candidates[chosen].service.weight
+        );
+        chosen
+    }
+}
+
+// draw two services by weight and take the less loaded one
+pub struct PowerOfTwoChoices;
+
+impl BalancingStrategy for PowerOfTwoChoices {
+    fn name(&self) -> &'static str {
+        "power_of_two"
+    }
+
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        let weights = effective_weights(candidates);
+        let first = weighted_draw(&weights, None).unwrap_or(0);
+        let Some(second) = weighted_draw(&weights, Some(first)) else {
+            debug!(
+                "selected service '{}' (power of two: only choice)",
+                candidates[first].service.name
+            );
+            return first;
+        };
+
+        let chosen = if candidates[second].in_flight < candidates[first].in_flight {
+            second
+        } else {
+            first
+        };
+        debug!(
+            "selected service '{}' (power of two: '{}' {} in flight vs '{}' {} in flight)",
+            candidates[chosen].service.name,
+            candidates[first].service.name,
+            candidates[first].in_flight,
+            candidates[second].service.name,
+            candidates[second].in_flight
+        );
+        chosen
+    }
+}
+
+// expected latency of a backend - time to first byte counts twice since it is
+// what a streaming client waits on before anything happens
+fn expected_latency_ms(latency: &LatencyStatus) -> f64 {
+    latency.ttfb_ms + latency.total_ms
+}
+
+// pick the service expected to answer soonest: its peak-ewma latency times the
+// requests it is already running, divided by its weight
+pub struct PeakEwmaLatency;
+
+impl BalancingStrategy for PeakEwmaLatency {
+    fn name(&self) -> &'static str {
+        "peak_ewma"
+    }
+
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        let weights = effective_weights(candidates);
+
+        // services without samples yet are assumed to be average, so they get tried
+        let known: Vec<f64> = candidates
+            .iter()
+            .filter_map(|c| c.latency.as_ref().map(expected_latency_ms))
+            .collect();
+        let unknown_latency = if known.is_empty() {
+            1.0
+        } else {
+            known.iter().sum::<f64>() / known.len() as f64
+        };
+
+        let mut best: Vec<usize> = Vec::new();
+        let mut best_cost = f64::MAX;
+        for (i, candidate) in candidates.iter().enumerate() {
+            if weights[i] == 0 {
+                continue;
+            }
+            let latency = candidate
+                .latency
+                .as_ref()
+                .map_or(unknown_latency, expected_latency_ms);
+            let cost = latency * (candidate.in_flight + 1) as f64 / weights[i] as f64;
+            if cost < best_cost {
+                best_cost = cost;
+                best.clear();
+                best.push(i);
+            } else if cost == best_cost {
+                best.push(i);
+            }
+        }
+
+        let chosen = best[rand::rng().random_range(0..best.len())];
+        let latency = candidates[chosen]
+            .latency
+            .as_ref()
+            .map(|l| format!("ttfb {:.0}ms, total {:.0}ms", l.ttfb_ms, l.total_ms))
+            .unwrap_or_else(|| "no samples".to_string());
+        debug!(
+            "selected service '{}' (peak ewma: {}, {} in flight, weight: {})",
+            candidates[chosen].service.name,
+            latency,
+            candidates[chosen].in_flight,
+            candidates[chosen].service.weight
+        );
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, weight: u32) -> Service {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "weight": weight,
+            "ip": "127.0.0.1",
+            "port": 8080,
+        }))
+        .unwrap()
+    }
+
+    fn candidates<'a>(services: &'a [Service], in_flight: &[usize]) -> Vec<Candidate<'a>> {
+        services
+            .iter()
+            .zip(in_flight)
+            .map(|(service, in_flight)| Candidate {
+                service,
+                in_flight: *in_flight,
+                latency: None,
+            })
+            .collect()
+    }
+
+    // how often each candidate is picked in `rounds` selections
+    fn picks(
+        strategy: &dyn BalancingStrategy,
+        candidates: &[Candidate],
+        rounds: usize,
+    ) -> Vec<usize> {
+        let mut picks = vec![0; candidates.len()];
+        for _ in 0..rounds {
+            picks[strategy.select(candidates)] += 1;
+        }
+        picks
+    }
+
+    fn latency(ms: f64) -> Option<LatencyStatus> {
+        Some(LatencyStatus {
+            ttfb_ms: ms,
+            total_ms: ms,
+            samples: 10,
+        })
+    }
+
+    #[test]
+    fn weighted_draw_follows_weights_and_skips() {
+        let weights = [3, 1, 0];
+        let mut counts = [0; 3];
+        for _ in 0..4000 {
+            counts[weighted_draw(&weights, None).unwrap()] += 1;
+        }
+        assert_eq!(counts[2], 0);
+        assert!((2700..3300).contains(&counts[0]), "{:?}", counts);
+
+        for _ in 0..100 {
+            assert_eq!(weighted_draw(&weights, Some(0)), Some(1));
+        }
+        assert_eq!(weighted_draw(&[0, 5], Some(1)), None);
+        assert_eq!(weighted_draw(&[], None), None);
+    }
+
+    #[test]
+    fn large_weights_do_not_overflow() {
+        let weights = [u32::MAX, u32::MAX, 1];
+        for _ in 0..100 {
+            assert!(weighted_draw(&weights, Some(0)).is_some_and(|i| i != 0));
+        }
+        let services = [service("a", u32::MAX), service("b", u32::MAX)];
+        let counts = picks(&WeightedRandom, &candidates(&services, &[0, 0]), 1000);
+        assert!(counts.iter().all(|c| *c > 350), "{:?}", counts);
+    }
+
+    #[test]
+    fn weighted_random_proportions() {
+        let services = [service("a", 3), service("b", 1)];
+        let counts = picks(&WeightedRandom, &candidates(&services, &[0, 0]), 4000);
+        assert!((2700..3300).contains(&counts[0]), "{:?}", counts);
+
+        // without any weight the first service takes everything
+        let services = [service("a", 0), service("b", 0)];
+        assert_eq!(
+            picks(&WeightedRandom, &candidates(&services, &[0, 0]), 10),
+            vec![10, 0]
+        );
+    }
+
+    #[test]
+    fn smooth_round_robin_interleaves() {
+        let strategy = WeightedRoundRobin::default();
+        let services = [service("a", 5), service("b", 1), service("c", 1)];
+        let pool = candidates(&services, &[0, 0, 0]);
+        let order: Vec<usize> = (0..14).map(|_| strategy.select(&pool)).collect();
+        assert_eq!(order, vec![0, 0, 1, 0, 2, 0, 0, 0, 0, 1, 0, 2, 0, 0]);
+
+        // zero weights all around count as equal
+        let strategy = WeightedRoundRobin::default();
+        let services = [service("a", 0), service("b", 0)];
+        assert_eq!(
+            picks(&strategy, &candidates(&services, &[0, 0]), 10),
+            vec![5, 5]
+        );
+    }
+
+    #[test]
+    fn least_outstanding_relative_to_weight() {
+        let services = [service("a", 2), service("b", 1), service("c", 0)];
+        // 4/2 vs 1/1 - "b" is less loaded for its weight, "c" never takes requests
+        assert_eq!(
+            picks(&LeastOutstanding, &candidates(&services, &[4, 1, 0]), 20),
+            vec![0, 20, 0]
+        );
+        // 2/2 vs 1/1 is a tie, shared at random
+        let counts = picks(&LeastOutstanding, &candidates(&services, &[2, 1, 0]), 400);
+        assert_eq!(counts[2], 0);
+        assert!(counts[0] > 100 && counts[1] > 100, "{:?}", counts);
+    }
+
+    #[test]
+    fn power_of_two_takes_the_less_loaded() {
+        // with two services both are always drawn, so the idle one wins every time
+        let services = [service("a", 1), service("b", 1)];
+        assert_eq!(
+            picks(&PowerOfTwoChoices, &candidates(&services, &[5, 0]), 50),
+            vec![0, 50]
+        );
+        // a zero-weight service is never drawn, however idle it is
+        let services = [service("a", 1), service("b", 1), service("c", 0)];
+        let counts = picks(&PowerOfTwoChoices, &candidates(&services, &[3, 3, 0]), 200);
+        assert_eq!(counts[2], 0);
+        // a single service is the only choice
+        let services = [service("a", 1)];
+        assert_eq!(PowerOfTwoChoices.select(&candidates(&services, &[9])), 0);
+    }
+
+    #[test]
+    fn peak_ewma_prefers_the_fastest_for_its_load() {
+        let services = [service("a", 1), service("b", 1), service("c", 2)];
+        let mut pool = candidates(&services, &[0, 0, 0]);
+        pool[0].latency = latency(100.0);
+        pool[1].latency = latency(400.0);
+        pool[2].latency = latency(300.0);
+        assert_eq!(picks(&PeakEwmaLatency, &pool, 20), vec![20, 0, 0]);
+
+        // 100ms with 4 in flight costs five times as much, more than 300ms idle at weight 2
+        pool[0].in_flight = 4;
+        assert_eq!(picks(&PeakEwmaLatency, &pool, 20), vec![0, 0, 20]);
+
+        // a service without samples is assumed to be average (450ms here), its higher
+        // weight still makes it the cheapest
+        pool[0].in_flight = 0;
+        pool[2].latency = None;
+        pool[0].latency = latency(500.0);
+        assert_eq!(picks(&PeakEwmaLatency, &pool, 20), vec![0, 0, 20]);
+    }
+}
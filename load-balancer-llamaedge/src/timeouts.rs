@@ -0,0 +1,266 @@
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// header a client can send to get more (or less) time for a single request, in seconds
+pub const OVERRIDE_HEADER: &str = "x-request-timeout";
+
+// how long each phase of a request may take, a zero duration disables that timeout
+#[derive(Debug, Clone)]
+pub struct TimeoutSettings {
+    // from the first byte of a request until its headers are complete - slow clients get `408`
+    pub client_header: Duration,
+    // from the end of the headers until the request body is complete, also `408`
+    pub client_body: Duration,
+    // opening a new connection to a backend
+    pub connect: Duration,
+    // from sending the request until the backend's response head arrives, a non-streaming
+    // completion is generated in full before that
+    pub first_byte: Duration,
+    // longest gap between two pieces of a response body, and longest a client may take
+    // to accept one
+    pub idle: Duration,
+    // the whole request from the end of its headers, across retries
+    pub total: Duration,
+    // cap on what a client can ask for through `X-Request-Timeout`
+    pub max_override: Duration,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        Self {
+            client_header: Duration::from_secs(10),
+            client_body: Duration::from_secs(60),
+            connect: Duration::from_secs(5),
+            first_byte: Duration::from_secs(300),
+            idle: Duration::from_secs(60),
+            total: Duration::from_secs(600),
+            max_override: Duration::from_secs(3600),
+        }
+    }
+}
+
+// which timeout fired, used as the `kind` label of `lb_timeouts_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    ClientHeader,
+    ClientBody,
+    // the client stopped reading the response
+    ClientWrite,
+    Connect,
+    FirstByte,
+    Idle,
+    Total,
+}
+
+impl TimeoutKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TimeoutKind::ClientHeader => "client_header",
+            TimeoutKind::ClientBody => "client_body",
+            TimeoutKind::ClientWrite => "client_write",
+            TimeoutKind::Connect => "connect",
+            TimeoutKind::FirstByte => "first_byte",
+            TimeoutKind::Idle => "idle",
+            TimeoutKind::Total => "total",
+        }
+    }
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// `None` for a disabled (zero) timeout
+pub fn enabled(timeout: Duration) -> Option<Duration> {
+    (!timeout.is_zero()).then_some(timeout)
+}
+
+// the timeouts of one proxied request, the deadline starts once its headers are read and
+// is shared by reading its body and all of its attempts
+#[derive(Debug, Clone)]
+pub struct RequestTimeouts {
+    pub connect: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub idle: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl RequestTimeouts {
+    // `requested` is the client's `X-Request-Timeout`, it replaces the total and first byte
+    // timeouts up to `max_override` - values that don't parse are ignored
+    pub fn new(settings: &TimeoutSettings, requested: Option<&str>) -> Self {
+        let requested = requested
+            .and_then(|secs| secs.trim().parse::<u64>().ok())
+            .filter(|secs| *secs > 0)
+            .map(|secs| Duration::from_secs(secs).min(settings.max_override));
+        let (first_byte, total) = match requested {
+            Some(requested) => (Some(requested), Some(requested)),
+            None => (enabled(settings.first_byte), enabled(settings.total)),
+        };
+        Self {
+            connect: enabled(settings.connect),
+            first_byte,
+            idle: enabled(settings.idle),
+            deadline: total.map(|total| Instant::now() + total),
+        }
+    }
+
+    // awaits `future` for at most `phase`, or until the request's deadline if that comes first
+    pub async fn run<F: Future>(
+        &self,
+        phase: Option<Duration>,
+        kind: TimeoutKind,
+        future: F,
+    ) -> Result<F::Output, TimeoutKind> {
+        let phase_end = phase.map(|phase| Instant::now() + phase);
+        let (end, kind) = match (phase_end, self.deadline) {
+            (Some(phase_end), Some(deadline)) if deadline < phase_end => {
+                (deadline, TimeoutKind::Total)
+            }
+            (Some(phase_end), _) => (phase_end, kind),
+            (None, Some(deadline)) => (deadline, TimeoutKind::Total),
+            (None, None) => return Ok(future.await),
+        };
+        tokio::time::timeout_at(end, future).await.map_err(|_| kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+
+    fn settings() -> TimeoutSettings {
+        TimeoutSettings {
+            first_byte: Duration::from_secs(300),
+            total: Duration::from_secs(600),
+            max_override: Duration::from_secs(3600),
+            ..TimeoutSettings::default()
+        }
+    }
+
+    fn remaining(timeouts: &RequestTimeouts) -> Duration {
+        timeouts.deadline.unwrap() - Instant::now()
+    }
+
+    #[test]
+    fn requested_timeout_replaces_first_byte_and_total() {
+        let timeouts = RequestTimeouts::new(&settings(), Some(" 30 "));
+        assert_eq!(timeouts.first_byte, Some(Duration::from_secs(30)));
+        assert!(remaining(&timeouts) <= Duration::from_secs(30));
+        // connect and idle are not affected
+        assert_eq!(timeouts.connect, Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.idle, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn requested_timeout_is_capped() {
+        let timeouts = RequestTimeouts::new(&settings(), Some("7200"));
+        assert_eq!(timeouts.first_byte, Some(Duration::from_secs(3600)));
+        assert!(remaining(&timeouts) <= Duration::from_secs(3600));
+        assert!(remaining(&timeouts) > Duration::from_secs(600));
+    }
+
+    #[test]
+    fn invalid_or_zero_requests_keep_the_settings() {
+        for requested in [
+            None,
+            Some("abc"),
+            Some("-5"),
+            Some("1.5"),
+            Some(""),
+            Some("0"),
+        ] {
+            let timeouts = RequestTimeouts::new(&settings(), requested);
+            assert_eq!(
+                timeouts.first_byte,
+                Some(Duration::from_secs(300)),
+                "{requested:?}"
+            );
+            assert!(
+                remaining(&timeouts) > Duration::from_secs(300),
+                "{requested:?}"
+            );
+            assert!(
+                remaining(&timeouts) <= Duration::from_secs(600),
+                "{requested:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn zero_settings_disable_the_timeouts() {
+        let settings = TimeoutSettings {
+            connect: Duration::ZERO,
+            first_byte: Duration::ZERO,
+            idle: Duration::ZERO,
+            total: Duration::ZERO,
+            ..settings()
+        };
+        let timeouts = RequestTimeouts::new(&settings, None);
+        assert_eq!(timeouts.connect, None);
+        assert_eq!(timeouts.first_byte, None);
+        assert_eq!(timeouts.idle, None);
+        assert!(timeouts.deadline.is_none());
+    }
+
+    #[tokio::test]
+    async fn phase_timeout_fires_before_a_later_deadline() {
+        let timeouts = RequestTimeouts::new(&settings(), None);
+        let result = timeouts
+            .run(
+                Some(Duration::from_millis(20)),
+                TimeoutKind::FirstByte,
+                sleep(Duration::from_secs(5)),
+            )
+            .await;
+        assert_eq!(result, Err(TimeoutKind::FirstByte));
+    }
+
+    #[tokio::test]
+    async fn deadline_fires_before_a_later_phase_timeout() {
+        let settings = TimeoutSettings {
+            total: Duration::from_millis(20),
+            ..settings()
+        };
+        let timeouts = RequestTimeouts::new(&settings, None);
+        let result = timeouts
+            .run(
+                Some(Duration::from_secs(5)),
+                TimeoutKind::Idle,
+                sleep(Duration::from_secs(5)),
+            )
+            .await;
+        assert_eq!(result, Err(TimeoutKind::Total));
+        // without a phase timeout the deadline still applies
+        let result = timeouts
+            .run(None, TimeoutKind::Connect, sleep(Duration::from_secs(5)))
+            .await;
+        assert_eq!(result, Err(TimeoutKind::Total));
+    }
+
+    #[tokio::test]
+    async fn finished_futures_return_their_output() {
+        let timeouts = RequestTimeouts::new(&settings(), None);
+        let result = timeouts
+            .run(Some(Duration::from_secs(5)), TimeoutKind::Connect, async {
+                7
+            })
+            .await;
+        assert_eq!(result, Ok(7));
+        let unlimited = RequestTimeouts::new(
+            &TimeoutSettings {
+                total: Duration::ZERO,
+                ..settings()
+            },
+            None,
+        );
+        assert_eq!(
+            unlimited.run(None, TimeoutKind::Idle, async { 8 }).await,
+            Ok(8)
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use crate::proxy::Usage;
+use crate::time::{civil_from_days, unix_now};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const HOUR: u64 = 3600;
+
+// name usage is accounted under for requests made without an api key
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Debug, Clone)]
+pub struct UsageSettings {
+    // json file the usage is written to and read back from at startup, unset keeps it in memory
+    pub file: Option<String>,
+    // how often the usage is written to `file`
+    pub flush_interval: Duration,
+    // hourly records older than this are dropped
+    pub retention: Duration,
+}
+
+impl Default for UsageSettings {
+    fn default() -> Self {
+        Self {
+            file: None,
+            flush_interval: Duration::from_secs(60),
+            retention: Duration::from_secs(90 * 24 * HOUR),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct UsageCounters {
+    pub requests: u64,
+    pub errors: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl UsageCounters {
+    fn add(&mut self, other: &UsageCounters) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+// one hour of usage by one key on one service, the unit kept in memory and on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UsageRecord {
+    // unix timestamp (seconds) of the start of the hour
+    hour: u64,
+    key: String,
+    service: String,
+    #[serde(flatten)]
+    counters: UsageCounters,
+}
+
+// which records `GET /api/usage` adds up
+#[derive(Debug, Clone, Default)]
+pub struct UsageFilter {
+    // unix timestamps (seconds), hours overlapping `since..until` are included
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub key: Option<String>,
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub total: UsageCounters,
+    pub keys: BTreeMap<String, UsageCounters>,
+    pub services: BTreeMap<String, UsageCounters>,
+}
+
+// unix timestamp of the first second of the utc calendar month `at` falls in
+pub fn month_start(at: u64) -> u64 {
+    let days = (at / 86_400) as i64;
+    let (_, _, day) = civil_from_days(days);
+    ((days - (day as i64 - 1)) * 86_400) as u64
+}
+
+// tokens per key in the current calendar month, kept up to date as usage is recorded so
+// the quota check on every request doesn't have to add up the whole ledger
+#[derive(Default)]
+struct MonthTotals {
+    // `month_start` of the month counted
+    start: u64,
+    tokens: HashMap<String, u64>,
+}
+
+impl MonthTotals {
+    fn add(&mut self, at: u64, key: &str, tokens: u64) {
+        let start = month_start(at);
+        if start < self.start {
+            return;
+        }
+        if start > self.start {
+            self.start = start;
+            self.tokens.clear();
+        }
+        *self.tokens.entry(key.to_string()).or_default() += tokens;
+    }
+
+    fn get(&self, now: u64, key: &str) -> u64 {
+        if month_start(now) != self.start {
+            return 0;
+        }
+        self.tokens.get(key).copied().unwrap_or(0)
+    }
+}
+
+// prompt and completion tokens, request and error counts per api key and service
+#[derive(Default)]
+pub struct UsageLedger {
+    records: Mutex<HashMap<(u64, String, String), UsageCounters>>,
+    month: Mutex<MonthTotals>,
+}
+
+impl UsageLedger {
+    // `usage` is `None` for failed requests and backends that didn't report any
+    pub fn record(
+        &self,
+        key: Option<&str>,
+        service: &str,
+        usage: Option<&Usage>,
+        tokens: u64,
+        failed: bool,
+    ) {
+        let now = unix_now();
+        let key = key.unwrap_or(ANONYMOUS);
+        let counters = UsageCounters {
+            requests: 1,
+            errors: failed as u64,
+            prompt_tokens: usage.map_or(0, |u| u.prompt_tokens),
+            completion_tokens: usage.map_or(tokens, |u| u.completion_tokens),
+            total_tokens: tokens,
+        };
+        self.records
+            .lock()
+            .unwrap()
+            .entry((now - now % HOUR, key.to_string(), service.to_string()))
+            .or_default()
+            .add(&counters);
+        self.month.lock().unwrap().add(now, key, tokens);
+    }
+
+    // tokens `key` used since the start of the current month
+    pub fn month_tokens(&self, key: &str) -> u64 {
+        self.month.lock().unwrap().get(unix_now(), key)
+    }
+
+    pub fn report(&self, filter: &UsageFilter) -> UsageReport {
+        let mut report = UsageReport {
+            since: filter.since,
+            until: filter.until,
+            total: UsageCounters::default(),
+            keys: BTreeMap::new(),
+            services: BTreeMap::new(),
+        };
+        let records = self.records.lock().unwrap();
+        let matching = records.iter().filter(|((hour, key, service), _)| {
+            filter.since.is_none_or(|since| hour + HOUR > since)
+                && filter.until.is_none_or(|until| *hour < until)
+                && filter.key.as_ref().is_none_or(|k| k == key)
+                && filter.service.as_ref().is_none_or(|s| s == service)
+        });
+        for ((_, key, service), counters) in matching {
+            report.total.add(counters);
+            report.keys.entry(key.clone()).or_default().add(counters);
+            report
+                .services
+                .entry(service.clone())
+                .or_default()
+                .add(counters);
+        }
+        report
+    }
+
+    // drops records older than the retention period
+    pub fn prune(&self, settings: &UsageSettings) {
+        let cutoff = unix_now().saturating_sub(settings.retention.as_secs());
+        self.records
+            .lock()
+            .unwrap()
+            .retain(|(hour, _, _), _| *hour + HOUR > cutoff);
+    }
+
+    pub fn load_file(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let loaded: Vec<UsageRecord> = serde_json::from_str(&contents)?;
+        let count = loaded.len();
+        let mut records = self.records.lock().unwrap();
+        let mut month = self.month.lock().unwrap();
+        for record in loaded {
+            month.add(record.hour, &record.key, record.counters.total_tokens);
+            records
+                .entry((record.hour, record.key, record.service))
+                .or_default()
+                .add(&record.counters);
+        }
+        Ok(count)
+    }
+
+    // writes to a temporary file first so a crash mid-write keeps the previous file
+    pub fn save_file(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut records: Vec<UsageRecord> = self
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((hour, key, service), counters)| UsageRecord {
+                hour: *hour,
+                key: key.clone(),
+                service: service.clone(),
+                counters: *counters,
+            })
+            .collect();
+        records.sort_by(|a, b| (a.hour, &a.key, &a.service).cmp(&(b.hour, &b.key, &b.service)));
+        let temp = format!("{}.tmp", path);
+        std::fs::write(&temp, serde_json::to_vec(&records)?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(records.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    #[test]
+    fn month_tokens_add_up_per_key() {
+        let ledger = UsageLedger::default();
+        ledger.record(Some("team-a"), "svc-1", Some(&usage(3, 4)), 7, false);
+        ledger.record(Some("team-a"), "svc-2", None, 5, false);
+        ledger.record(Some("team-b"), "svc-1", None, 0, true);
+        ledger.record(None, "svc-1", Some(&usage(1, 1)), 2, false);
+        assert_eq!(ledger.month_tokens("team-a"), 12);
+        assert_eq!(ledger.month_tokens("team-b"), 0);
+        assert_eq!(ledger.month_tokens(ANONYMOUS), 2);
+    }
+
+    #[test]
+    fn month_totals_start_over_each_month() {
+        let mut totals = MonthTotals::default();
+        // 2024-01-31 and 2024-02-01
+        let january = 1_706_659_200;
+        let february = 1_706_745_600;
+        totals.add(january, "team-a", 10);
+        assert_eq!(totals.get(january, "team-a"), 10);
+        totals.add(february, "team-a", 5);
+        // a record of the month before, e.g. read back from the usage file
+        totals.add(january, "team-a", 10);
+        assert_eq!(totals.get(february, "team-a"), 5);
+        assert_eq!(totals.get(february + 31 * 86_400, "team-a"), 0);
+    }
+
+    #[test]
+    fn report_filters_by_key_and_service() {
+        let ledger = UsageLedger::default();
+        ledger.record(Some("team-a"), "svc-1", Some(&usage(3, 4)), 7, false);
+        ledger.record(Some("team-a"), "svc-2", None, 5, true);
+        ledger.record(Some("team-b"), "svc-1", None, 1, false);
+
+        let all = ledger.report(&UsageFilter::default());
+        assert_eq!(all.total.requests, 3);
+        assert_eq!(all.total.errors, 1);
+        assert_eq!(all.total.total_tokens, 13);
+        assert_eq!(all.services["svc-1"].prompt_tokens, 3);
+
+        let filter = UsageFilter {
+            key: Some("team-a".to_string()),
+            service: Some("svc-1".to_string()),
+            ..UsageFilter::default()
+        };
+        let report = ledger.report(&filter);
+        assert_eq!(report.total.total_tokens, 7);
+        assert_eq!(report.keys.len(), 1);
+
+        let future = UsageFilter {
+            since: Some(unix_now() + 2 * HOUR),
+            ..UsageFilter::default()
+        };
+        assert_eq!(ledger.report(&future).total.requests, 0);
+    }
+
+    #[test]
+    fn month_start_is_utc_midnight_of_the_first() {
+        // 2024-02-29 13:00 utc
+        assert_eq!(month_start(1_709_211_600), 1_706_745_600);
+        assert_eq!(month_start(1_706_745_600), 1_706_745_600);
+    }
+}
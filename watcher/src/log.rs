@@ -0,0 +1,186 @@
+// k8s-openapi re-exports the chrono it is built with
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// leveled logging in the same json lines as the lb, one object per line on stdout
+// (stderr for errors and warnings)
+//
+// lines logged during a sync pass or event carry the request id sent along with its
+// lb admin calls, so they can be matched with the lb's logs
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+const LEVEL_NAMES: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            other => Err(format!("unknown log level '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(LEVEL_NAMES[*self as usize - 1])
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+fn set_level(new_level: Level) {
+    let old_level = level();
+    LEVEL.store(new_level as u8, Ordering::Relaxed);
+    if old_level != new_level {
+        emit(Level::Info, module_path!(), format_args!("log level changed: {} -> {}", old_level, new_level));
+    }
+}
+
+// `WATCHER_LOG_LEVEL` at startup, defaults to info
+pub fn init_from_env() {
+    if let Ok(value) = std::env::var("WATCHER_LOG_LEVEL") {
+        match value.parse() {
+            Ok(level) => set_level(level),
+            Err(err) => emit(Level::Warn, module_path!(), format_args!("ignoring WATCHER_LOG_LEVEL: {}", err)),
+        }
+    }
+    reload_level_file();
+}
+
+// the level in `WATCHER_LOG_LEVEL_FILE` (e.g. a mounted configmap key) if it is set,
+// re-read on every sync so the level can be changed without restarting the watcher
+pub fn reload_level_file() {
+    let Ok(path) = std::env::var("WATCHER_LOG_LEVEL_FILE") else {
+        return;
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(contents) if contents.trim().is_empty() => {}
+        Ok(contents) => match contents.parse() {
+            Ok(level) => set_level(level),
+            Err(err) => emit(Level::Warn, module_path!(), format_args!("ignoring log level file {}: {}", path, err)),
+        },
+        Err(err) => emit(Level::Debug, module_path!(), format_args!("can't read log level file {}: {}", path, err)),
+    }
+}
+
+// 16 hex digits, unique within this process
+pub fn generate_request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}", nanos.rotate_left(16) ^ count)
+}
+
+// the id of the sync pass or event being handled, sent to the lb as `X-Request-Id`
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// runs `future` with every line it logs tagged with a fresh request id
+pub async fn with_request_id<F: std::future::Future>(future: F) -> F::Output {
+    REQUEST_ID.scope(generate_request_id(), future).await
+}
+
+// `2024-05-01T12:34:56.789Z`
+fn timestamp() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    ts: String,
+    level: Level,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    msg: String,
+}
+
+pub fn emit(level: Level, target: &str, message: fmt::Arguments<'_>) {
+    if level as u8 > LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    let line = Line {
+        ts: timestamp(),
+        level,
+        target,
+        request_id: current_request_id(),
+        msg: message.to_string(),
+    };
+    let Ok(json) = serde_json::to_string(&line) else {
+        return;
+    };
+    if level <= Level::Warn {
+        eprintln!("{}", json);
+    } else {
+        println!("{}", json);
+    }
+}
+
+// declared with `#[macro_use]` ahead of everything else so these are in scope in main.rs,
+// a `use` can't import `warn` since it clashes with the builtin attribute
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Error, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Warn, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Info, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Debug, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Trace, module_path!(), format_args!($($arg)*))
+    };
+}
@@ -1,9 +1,16 @@
+#[macro_use]
+mod log;
+
 use futures::StreamExt;
-use k8s_openapi::api::core::v1::Service; // kubernetes service type
+use k8s_openapi::api::core::v1::{Secret, Service}; // kubernetes service type
 use kube::{api::ListParams, runtime::watcher, Api, Client, ResourceExt};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use reqwest::Client as HttpClient;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
 use tokio::net::lookup_host;
 use tokio::time::{interval, Duration};
 
@@ -13,6 +20,13 @@ struct RegisterPayload {
     weight: u32,  
     ip: String,   
     port: u16,   
+    models: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_concurrency: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pools: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -21,21 +35,156 @@ struct RegisteredService {
     weight: u32,
     ip: String,
     port: u16,
+    #[serde(default)]
+    models: Vec<String>,
+    #[serde(default)]
+    max_concurrency: Option<u32>,
+    #[serde(default)]
+    pools: Vec<String>,
+    // the lb only lists the header names, values come back redacted
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+}
+
+// split a comma-separated annotation such as `llamaedge/models` into its entries
+fn parse_list(annotations: &BTreeMap<String, String>, key: &str) -> Vec<String> {
+    annotations
+        .get(key)
+        .map(|m| {
+            m.split(',')
+                .map(|entry| entry.trim().to_string())
+                .filter(|entry| !entry.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// get served models from the comma-separated `llamaedge/models` annotation
+fn parse_models(annotations: &BTreeMap<String, String>) -> Vec<String> {
+    parse_list(annotations, "llamaedge/models")
+}
+
+// get the lb route pools (chat, embeddings, audio, images) from the `llamaedge/pools` annotation
+fn parse_pools(annotations: &BTreeMap<String, String>) -> Vec<String> {
+    parse_list(annotations, "llamaedge/pools")
+}
+
+// get the headers the lb sets on requests to this backend (e.g. its own api key) from the
+// secret named in the `llamaedge/headers-secret` annotation, one header per secret key
+async fn read_header_secret(
+    client: &Client,
+    namespace: &str,
+    annotations: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let Some(secret_name) = annotations.get("llamaedge/headers-secret") else {
+        return BTreeMap::new();
+    };
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
+    match secrets.get(secret_name.trim()).await {
+        Ok(secret) => secret
+            .data
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, String::from_utf8(value.0).ok()?.trim().to_string())))
+            .collect(),
+        Err(err) => {
+            warn!("failed to read headers secret {}/{}: {}", namespace, secret_name, err);
+            BTreeMap::new()
+        }
+    }
+}
+
+// digest of the header values last registered per service - the lb only lists header
+// names, so this is how the sync notices a value changed in the secret
+static HEADER_DIGESTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+fn header_digest(headers: &BTreeMap<String, String>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    headers.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn remember_headers(name: &str, headers: &BTreeMap<String, String>) {
+    HEADER_DIGESTS.lock().unwrap().insert(name.to_string(), header_digest(headers));
+}
+
+fn forget_headers(name: &str) {
+    HEADER_DIGESTS.lock().unwrap().remove(name);
+}
+
+// true unless these are the headers last registered for the service
+fn headers_changed(name: &str, headers: &BTreeMap<String, String>) -> bool {
+    HEADER_DIGESTS.lock().unwrap().get(name) != Some(&header_digest(headers))
+}
+
+// read the lb admin token from the mounted secret, `LB_ADMIN_TOKEN_FILE` overrides the path
+fn read_admin_token() -> Option<String> {
+    let path = std::env::var("LB_ADMIN_TOKEN_FILE")
+        .unwrap_or_else(|_| "/var/run/secrets/llamaedge/token".to_string());
+    match std::fs::read_to_string(&path) {
+        Ok(token) if !token.trim().is_empty() => {
+            info!("admin token loaded from {}", path);
+            Some(token.trim().to_string())
+        }
+        Ok(_) => {
+            warn!("admin token file {} is empty, sending requests without it", path);
+            None
+        }
+        Err(err) => {
+            info!("no admin token at {} ({}), sending requests without it", path, err);
+            None
+        }
+    }
+}
+
+// http client for the lb admin api, every request carries the admin token if there is one
+fn build_http_client() -> anyhow::Result<HttpClient> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = read_admin_token() {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    Ok(HttpClient::builder().default_headers(headers).build()?)
+}
+
+// point at the likely cause when the lb turns the admin credential down
+fn report_auth_failure(status: reqwest::StatusCode) {
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        warn!("lb rejected the admin request - check the admin token secret and the lb's LB_ADMIN_* settings");
+    }
+}
+
+// tag an lb admin call with the request id of the sync pass or event it is made for
+fn tag_request(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match log::current_request_id() {
+        Some(id) => request.header("X-Request-Id", id),
+        None => request,
+    }
+}
+
+// get the per-backend request limit from the `llamaedge/max-concurrency` annotation
+fn parse_max_concurrency(annotations: &BTreeMap<String, String>) -> Option<u32> {
+    annotations
+        .get("llamaedge/max-concurrency")
+        .and_then(|c| c.trim().parse::<u32>().ok())
+        .filter(|c| *c > 0)
 }
 
 async fn register_service(
     svc: &Service,
+    client: &Client,
     http: &HttpClient,
     context: &str, // ,ie. startup, reconciliation, or event
 ) -> anyhow::Result<()> {
     let name = svc.name_any();
     let namespace = svc.namespace().unwrap_or("default".to_string());
-    println!("processing {} service: {}/{}", context, namespace, name);
+    info!("processing {} service: {}/{}", context, namespace, name);
 
     // get annotations
     let annotations = svc.metadata.annotations.clone().unwrap_or_default();
     if context == "event" {
-        println!("service annotations: {:?}", annotations);
+        trace!("service annotations: {:?}", annotations);
     }
 
     // get weight from annotation
@@ -45,9 +194,35 @@ async fn register_service(
         .unwrap_or(1);
 
     if annotations.contains_key("llamaedge/weight") {
-        println!("weight found in annotations: {}", weight);
+        debug!("weight found in annotations: {}", weight);
     } else {
-        println!("no weight annotation found, using default: {}", weight);
+        debug!("no weight annotation found, using default: {}", weight);
+    }
+
+    // get served models from annotation - empty means the lb routes any model here
+    let models = parse_models(&annotations);
+    if models.is_empty() {
+        debug!("no models annotation found, service accepts any model");
+    } else {
+        debug!("models found in annotations: {:?}", models);
+    }
+
+    // get concurrency limit from annotation - unset means no limit
+    let max_concurrency = parse_max_concurrency(&annotations);
+    if let Some(limit) = max_concurrency {
+        debug!("max concurrency found in annotations: {}", limit);
+    }
+
+    // get route pools from annotation - empty means the lb sends it any request
+    let pools = parse_pools(&annotations);
+    if !pools.is_empty() {
+        debug!("pools found in annotations: {:?}", pools);
+    }
+
+    // get headers to inject from the annotated secret - only names are logged
+    let headers = read_header_secret(client, &namespace, &annotations).await;
+    if !headers.is_empty() {
+        debug!("headers found in secret: {:?}", headers.keys().collect::<Vec<_>>());
     }
 
     // get service port
@@ -55,7 +230,7 @@ async fn register_service(
     if let Some(spec) = &svc.spec {
         if let Some(ports) = &spec.ports {
             if context == "event" {
-                println!(
+                debug!(
                     "service ports: {:?}",
                     ports
                         .iter()
@@ -71,19 +246,19 @@ async fn register_service(
             // use the first port if available
             if let Some(first_port) = ports.first() {
                 service_port = first_port.port as u16;
-                println!("using port {} for DNS resolution", service_port);
+                debug!("using port {} for DNS resolution", service_port);
             }
         }
         if let Some(cluster_ip) = &spec.cluster_ip {
             if context == "event" {
-                println!("service cluster ip: {}", cluster_ip);
+                debug!("service cluster ip: {}", cluster_ip);
             }
         }
     }
 
     // perform DNS resolution
     let hostname = format!("{}.{}.svc.cluster.local:{}", name, namespace, service_port);
-    println!("performing DNS lookup for {}: {}", context, hostname);
+    debug!("performing DNS lookup for {}: {}", context, hostname);
 
     let lookup_result = lookup_host(hostname.clone()).await;
     match lookup_result {
@@ -91,7 +266,7 @@ async fn register_service(
             if let Some(first_addr) = addrs.next() {
                 let ip = first_addr.ip().to_string();
                 let port = first_addr.port();
-                println!("DNS resolution successful: {}:{}", ip, port);
+                debug!("DNS resolution successful: {}:{}", ip, port);
 
                 // create payload for registration
                 let payload = RegisterPayload {
@@ -99,54 +274,58 @@ async fn register_service(
                     weight,
                     ip,
                     port,
+                    models,
+                    max_concurrency,
+                    pools,
+                    headers,
                 };
-                println!("preparing {} payload: {:?}", context, payload);
-                
-                if context == "event" {
-                    println!("payload being sent: {:?}", serde_json::to_string(&payload)?);
-                }
+                debug!("preparing {} payload for {} (weight: {}, models: {:?}, pools: {:?})", context, payload.name, payload.weight, payload.models, payload.pools);
 
                 // send POST request
                 let lb_url = "http://load-balancer-service.default.svc.cluster.local:8080/api/register";
-                println!("sending {} registration request to: {}", context, lb_url);
+                debug!("sending {} registration request to: {}", context, lb_url);
 
-                let res = http.post(lb_url).json(&payload).send().await;
+                let res = tag_request(http.post(lb_url)).json(&payload).send().await;
 
                 match res {
                     Ok(resp) => {
                         let status = resp.status();
-                        println!(
+                        info!(
                             "{} registration successful for {}/{}: http {}",
                             context, namespace, name, status
                         );
+                        report_auth_failure(status);
+                        if status.is_success() {
+                            remember_headers(&name, &payload.headers);
+                        }
 
                         // log response body if available (only for events to reduce noise)
                         if context == "event" {
                             if let Ok(body) = resp.text().await {
                                 if !body.is_empty() {
-                                    println!("response body: {}", body);
+                                    trace!("response body: {}", body);
                                 }
                             }
                         }
                     }
                     Err(err) => {
-                        eprintln!(
+                        error!(
                             "{} registration failed for {}/{}: {}",
                             context, namespace, name, err
                         );
                         if context == "event" {
-                            eprintln!("check if lb is running at: {}", lb_url);
+                            warn!("check if lb is running at: {}", lb_url);
                         }
                     }
                 }
             } else {
-                eprintln!("DNS resolution returned no addresses for {}: {}", context, hostname);
+                warn!("DNS resolution returned no addresses for {}: {}", context, hostname);
             }
         }
         Err(err) => {
-            eprintln!("DNS resolution failed for {} {}: {}", context, hostname, err);
+            error!("DNS resolution failed for {} {}: {}", context, hostname, err);
             if context == "event" {
-                eprintln!("check if the service exists and is accessible");
+                warn!("check if the service exists and is accessible");
             }
         }
     }
@@ -161,19 +340,22 @@ async fn get_services(
 ) -> anyhow::Result<Vec<Service>> {
     match services.list(lp).await {
         Ok(service_list) => {
-            println!("found {} services with label llamaedge/target=true", 
+            debug!("found {} services with label llamaedge/target=true", 
                     service_list.items.len());
             Ok(service_list.items)
         }
         Err(err) => {
-            eprintln!("failed to list services: {}", err);
+            error!("failed to list services: {}", err);
             Ok(Vec::new())
         }
     }
 }
 
+// weight, ip, port, models, max concurrency, pools and injected headers of a k8s service
+type ServiceInfo = (u32, String, u16, Vec<String>, Option<u32>, Vec<String>, BTreeMap<String, String>);
+
 // extract service info from service
-async fn extract_service_info(svc: &Service) -> Option<(String, u32, String, u16)> {
+async fn extract_service_info(svc: &Service, client: &Client) -> Option<(String, ServiceInfo)> {
     let name = svc.name_any();
     let namespace = svc.namespace().unwrap_or("default".to_string());
     
@@ -183,6 +365,10 @@ async fn extract_service_info(svc: &Service) -> Option<(String, u32, String, u16
         .get("llamaedge/weight")
         .and_then(|w| w.parse::<u32>().ok())
         .unwrap_or(1);
+    let models = parse_models(&annotations);
+    let max_concurrency = parse_max_concurrency(&annotations);
+    let pools = parse_pools(&annotations);
+    let headers = read_header_secret(client, &namespace, &annotations).await;
 
     // get service port
     let mut service_port = 8080u16;
@@ -201,14 +387,14 @@ async fn extract_service_info(svc: &Service) -> Option<(String, u32, String, u16
             if let Some(first_addr) = addrs.next() {
                 let ip = first_addr.ip().to_string();
                 let port = first_addr.port();
-                Some((name, weight, ip, port))
+                Some((name, (weight, ip, port, models, max_concurrency, pools, headers)))
             } else {
-                eprintln!("DNS resolution returned no addresses for: {}", name);
+                warn!("DNS resolution returned no addresses for: {}", name);
                 None
             }
         }
         Err(err) => {
-            eprintln!("DNS resolution failed for {}: {}", name, err);
+            error!("DNS resolution failed for {}: {}", name, err);
             None
         }
     }
@@ -218,12 +404,14 @@ async fn extract_service_info(svc: &Service) -> Option<(String, u32, String, u16
 async fn register_service_payload(payload: &RegisterPayload, http: &HttpClient) -> anyhow::Result<()> {
     let lb_url = "http://load-balancer-service.default.svc.cluster.local:8080/api/register";
     
-    let res = http.post(lb_url).json(payload).send().await?;
+    let res = tag_request(http.post(lb_url)).json(payload).send().await?;
     
     if res.status().is_success() {
-        println!("successfully registered/updated service: {}", payload.name);
+        info!("successfully registered/updated service: {}", payload.name);
+        remember_headers(&payload.name, &payload.headers);
     } else {
-        eprintln!("failed to register service {}: http {}", payload.name, res.status());
+        error!("failed to register service {}: http {}", payload.name, res.status());
+        report_auth_failure(res.status());
     }
     
     Ok(())
@@ -236,19 +424,20 @@ async fn sync_services_with_load_balancer(
     http: &HttpClient,
     context: &str,
 ) -> anyhow::Result<()> {
-    println!("starting service synchronization with lb ({})", context);
+    info!("starting service synchronization with lb ({})", context);
     
     // get current state from both sources
+    let client = services.clone().into_client();
     let k8s_services = get_services(services, lp).await?;
     let lb_services = get_registered_services(http).await?;
     
     // convert to maps for easier comparison
-    let mut k8s_service_map: HashMap<String, (u32, String, u16)> = HashMap::new();
+    let mut k8s_service_map: HashMap<String, ServiceInfo> = HashMap::new();
     
     // extract info from services
     for svc in &k8s_services {
-        if let Some((name, weight, ip, port)) = extract_service_info(svc).await {
-            k8s_service_map.insert(name, (weight, ip, port));
+        if let Some((name, info)) = extract_service_info(svc, &client).await {
+            k8s_service_map.insert(name, info);
         }
     }
     
@@ -257,99 +446,115 @@ async fn sync_services_with_load_balancer(
         lb_service_map.insert(svc.name.clone(), svc);
     }
     
-    println!("comparison: {} K8s services vs {} LB services", 
+    debug!("comparison: {} K8s services vs {} LB services", 
             k8s_service_map.len(), lb_service_map.len());
     
     // 1. handle services that exist in K8s but not in LB (need to register)
-    for (k8s_name, (weight, ip, port)) in &k8s_service_map {
+    for (k8s_name, (weight, ip, port, models, max_concurrency, pools, headers)) in &k8s_service_map {
         if !lb_service_map.contains_key(k8s_name) {
-            println!("service {} exists in K8s but not in LB - registering", k8s_name);
+            info!("service {} exists in K8s but not in LB - registering", k8s_name);
             
             let payload = RegisterPayload {
                 name: k8s_name.clone(),
                 weight: *weight,
                 ip: ip.clone(),
                 port: *port,
+                models: models.clone(),
+                max_concurrency: *max_concurrency,
+                pools: pools.clone(),
+                headers: headers.clone(),
             };
             
             if let Err(err) = register_service_payload(&payload, http).await {
-                eprintln!("failed to register missing service {}: {}", k8s_name, err);
+                error!("failed to register missing service {}: {}", k8s_name, err);
             }
         }
     }
     
     // 2. handle services that exist in LB but not in K8s (stale, need to remove)
-    for (lb_name, _) in &lb_service_map {
+    for lb_name in lb_service_map.keys() {
         if !k8s_service_map.contains_key(lb_name) {
-            println!("service {} exists in LB but not in K8s - removing stale registration", lb_name);
+            info!("service {} exists in LB but not in K8s - removing stale registration", lb_name);
             
             let unregister_url = format!(
                 "http://load-balancer-service.default.svc.cluster.local:8080/api/unregister/{}",
                 lb_name
             );
             
-            match http.delete(&unregister_url).send().await {
+            match tag_request(http.delete(&unregister_url)).send().await {
                 Ok(resp) => {
                     if resp.status().is_success() {
-                        println!("successfully removed stale service: {}", lb_name);
+                        info!("successfully removed stale service: {}", lb_name);
+                        forget_headers(lb_name);
                     } else {
-                        eprintln!("failed to remove stale service {}: http {}", lb_name, resp.status());
+                        error!("failed to remove stale service {}: http {}", lb_name, resp.status());
+                        report_auth_failure(resp.status());
                     }
                 }
                 Err(err) => {
-                    eprintln!("error removing stale service {}: {}", lb_name, err);
+                    error!("error removing stale service {}: {}", lb_name, err);
                 }
             }
         }
     }
     
     // 3. handle services that exist in both but might have different details (need to update)
-    for (k8s_name, (k8s_weight, k8s_ip, k8s_port)) in &k8s_service_map {
+    for (k8s_name, (k8s_weight, k8s_ip, k8s_port, k8s_models, k8s_max_concurrency, k8s_pools, k8s_headers)) in &k8s_service_map {
         if let Some(lb_service) = lb_service_map.get(k8s_name) {
             // compare details to see if update is needed
             let needs_update = lb_service.weight != *k8s_weight 
                             || lb_service.ip != *k8s_ip 
-                            || lb_service.port != *k8s_port;
+                            || lb_service.port != *k8s_port
+                            || lb_service.models != *k8s_models
+                            || lb_service.max_concurrency != *k8s_max_concurrency
+                            || lb_service.pools != *k8s_pools
+                            || !lb_service.headers.keys().eq(k8s_headers.keys())
+                            || headers_changed(k8s_name, k8s_headers);
                             
             if needs_update {
-                println!("service {} details changed - updating registration", k8s_name);
-                println!("old: weight={}, ip={}, port={}", 
-                        lb_service.weight, lb_service.ip, lb_service.port);
-                println!("new: weight={}, ip={}, port={}", 
-                        k8s_weight, k8s_ip, k8s_port);
+                info!("service {} details changed - updating registration", k8s_name);
+                debug!("old: weight={}, ip={}, port={}, models={:?}, max_concurrency={:?}, pools={:?}", 
+                        lb_service.weight, lb_service.ip, lb_service.port, lb_service.models, lb_service.max_concurrency, lb_service.pools);
+                debug!("new: weight={}, ip={}, port={}, models={:?}, max_concurrency={:?}, pools={:?}", 
+                        k8s_weight, k8s_ip, k8s_port, k8s_models, k8s_max_concurrency, k8s_pools);
                 
                 let payload = RegisterPayload {
                     name: k8s_name.clone(),
                     weight: *k8s_weight,
                     ip: k8s_ip.clone(),
                     port: *k8s_port,
+                    models: k8s_models.clone(),
+                    max_concurrency: *k8s_max_concurrency,
+                    pools: k8s_pools.clone(),
+                    headers: k8s_headers.clone(),
                 };
                 
                 if let Err(err) = register_service_payload(&payload, http).await {
-                    eprintln!("failed to update service {}: {}", k8s_name, err);
+                    error!("failed to update service {}: {}", k8s_name, err);
                 }
             }
         }
     }
     
-    println!("service sunc completed");
+    info!("service sync completed");
     Ok(())
 }
 
 // get currently registered services from lb
 async fn get_registered_services(http: &HttpClient) -> anyhow::Result<Vec<RegisteredService>> {
     let lb_url = "http://load-balancer-service.default.svc.cluster.local:8080/api/services";
-    println!("fetching currently registered services from: {}", lb_url);
+    debug!("fetching currently registered services from: {}", lb_url);
     
-    let res = http.get(lb_url).send().await?;
+    let res = tag_request(http.get(lb_url)).send().await?;
     
     if res.status().is_success() {
         let services: Vec<RegisteredService> = res.json().await?;
-        println!("lb has {} registered services", services.len());
+        debug!("lb has {} registered services", services.len());
         Ok(services)
     } else {
         let status = res.status();
-        eprintln!("failed to fetch registered services: http {}", status);
+        error!("failed to fetch registered services: http {}", status);
+        report_auth_failure(status);
         Ok(Vec::new()) // return empty vec on error to continue op
     }
 }
@@ -360,28 +565,29 @@ async fn reconcile_services(
     lp: &ListParams,
     http: &HttpClient,
 ) -> anyhow::Result<()> {
-    println!("starting periodic reconciliation of services...");
+    info!("starting periodic reconciliation of services...");
+    let client = services.clone().into_client();
     
     match services.list(lp).await {
         Ok(service_list) => {
-            println!("reconciliation found {} services with label llamaedge/target=true", 
+            info!("reconciliation found {} services with label llamaedge/target=true", 
                     service_list.items.len());
             
             if service_list.items.is_empty() {
-                println!("no services found during reconciliation");
+                info!("no services found during reconciliation");
                 return Ok(());
             }
             
             for svc in service_list.items {
-                if let Err(err) = register_service(&svc, http, "reconciliation").await {
-                    eprintln!("reconciliation failed for service: {}", err);
+                if let Err(err) = register_service(&svc, &client, http, "reconciliation").await {
+                    error!("reconciliation failed for service: {}", err);
                 }
             }
             
-            println!("reconciliation completed successfully");
+            info!("reconciliation completed successfully");
         }
         Err(err) => {
-            eprintln!("reconciliation failed to list services: {}", err);
+            error!("reconciliation failed to list services: {}", err);
         }
     }
     
@@ -390,161 +596,183 @@ async fn reconcile_services(
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    println!("starting service watcher for llamaedge lb");
+    log::init_from_env();
+    info!("starting service watcher for llamaedge lb");
 
     // create k8s client
-    println!("connecting to cluster...");
+    info!("connecting to cluster...");
     let k8s_client = Client::try_default().await?;
-    println!("successfully connected to cluster");
+    info!("successfully connected to cluster");
 
     // API interface for Services in all namespaces
-    let services: Api<Service> = Api::all(k8s_client);
-    println!("configured to watch services across all namespaces");
+    let services: Api<Service> = Api::all(k8s_client.clone());
+    info!("configured to watch services across all namespaces");
 
     // create HTTP client
-    let http = HttpClient::new();
-    println!("HTTP client initialized for lb communication");
+    let http = build_http_client()?;
+    info!("HTTP client initialized for lb communication");
 
     // only watch Services with label "llamaedge/target=true"
     let lp = ListParams::default().labels("llamaedge/target=true");
-    println!("label selector configured: llamaedge/target=true");
+    info!("label selector configured: llamaedge/target=true");
 
     // discover and register existing services
-    println!("discovering existing services with matching labels...");
-
-    match services.list(&lp).await {
-        Ok(service_list) => {
-            println!(
-                "found {} existing services with label llamaedge/target=true",
-                service_list.items.len()
-            );
+    log::with_request_id(async {
+        info!("discovering existing services with matching labels...");
+
+        match services.list(&lp).await {
+            Ok(service_list) => {
+                info!(
+                    "found {} existing services with label llamaedge/target=true",
+                    service_list.items.len()
+                );
 
-            if service_list.items.is_empty() {
-                println!("no existing services found to register");
-            } else {
-                for svc in service_list.items {
-                    if let Err(err) = register_service(&svc, &http, "startup").await {
-                        eprintln!("startup registration failed: {}", err);
+                if service_list.items.is_empty() {
+                    info!("no existing services found to register");
+                } else {
+                    for svc in service_list.items {
+                        if let Err(err) = register_service(&svc, &k8s_client, &http, "startup").await {
+                            error!("startup registration failed: {}", err);
+                        }
                     }
                 }
-            }
 
-            println!("finished processing existing services");
-        }
-        Err(err) => {
-            eprintln!("failed to discover existing services: {}", err);
-            eprintln!("continuing with watcher anyway...");
+                info!("finished processing existing services");
+            }
+            Err(err) => {
+                error!("failed to discover existing services: {}", err);
+                warn!("continuing with watcher anyway...");
+            }
         }
-    }
+    })
+    .await;
 
     // configure the watcher with label selector
     let watcher_config = watcher::Config::default().labels(&lp.label_selector.clone().unwrap_or_default());
 
     // start watching Services with config
     let mut watcher_stream = watcher(services.clone(), watcher_config).boxed();
-    println!("starting to watch services with label llamaedge/target=true");
+    info!("starting to watch services with label llamaedge/target=true");
 
     // set up periodic reconciliation and service sync
     let mut reconcile_timer = interval(Duration::from_secs(300)); // every 5 minutes
     let mut sync_timer = interval(Duration::from_secs(60)); // every 60 seconds
-    println!("periodic reconciliation configured: every 5 minutes");
-    println!("service sync configured: every 60 seconds");
-    println!("waiting for service events...");
+    info!("periodic reconciliation configured: every 5 minutes");
+    info!("service sync configured: every 60 seconds");
+    info!("waiting for service events...");
 
     loop {
         tokio::select! {
             // handle reconciliation timer
             _ = reconcile_timer.tick() => {
-                if let Err(err) = reconcile_services(&services, &lp, &http).await {
-                    eprintln!("reconciliation error: {}", err);
-                }
+                log::with_request_id(async {
+                    if let Err(err) = reconcile_services(&services, &lp, &http).await {
+                        error!("reconciliation error: {}", err);
+                    }
                 
-                // sync after reconciliation
-                if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "post-reconciliation").await {
-                    eprintln!("post-reconciliation sync error: {}", err);
-                }
+                    // sync after reconciliation
+                    if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "post-reconciliation").await {
+                        error!("post-reconciliation sync error: {}", err);
+                    }
+                })
+                .await;
             }
             
             // handle service sync timer
             _ = sync_timer.tick() => {
-                if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "periodic").await {
-                    eprintln!("periodic sync error: {}", err);
-                }
+                // pick up a log level changed through the level file
+                log::reload_level_file();
+                log::with_request_id(async {
+                    if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "periodic").await {
+                        error!("periodic sync error: {}", err);
+                    }
+                })
+                .await;
             }
             
             // handle watcher events
             event = watcher_stream.next() => {
                 match event {
                     Some(Ok(kube::runtime::watcher::Event::Applied(svc))) => {
-                        if let Err(err) = register_service(&svc, &http, "event").await {
-                            eprintln!("event registration failed: {}", err);
-                        } else {
-                            // sync services after successful registration
-                            if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "post-registration").await {
-                                eprintln!("post-registration sync failed: {}", err);
+                        log::with_request_id(async {
+                            if let Err(err) = register_service(&svc, &k8s_client, &http, "event").await {
+                                error!("event registration failed: {}", err);
+                            } else {
+                                // sync services after successful registration
+                                if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "post-registration").await {
+                                    error!("post-registration sync failed: {}", err);
+                                }
                             }
-                        }
+                        })
+                        .await;
                     }
 
                     Some(Ok(kube::runtime::watcher::Event::Deleted(svc))) => {
-                        // get service name and namespace
-                        let name = svc.name_any();
-                        let namespace = svc.namespace().unwrap_or("default".to_string());
-                        println!("service event: deleted - {}/{}", namespace, name);
-
-                        // send DELETE request to lb - using same hostname as registration
-                        let url = format!(
-                            "http://load-balancer-service.default.svc.cluster.local:8080/api/unregister/{}",
-                            name
-                        );
-                        println!("sending deregistration request to: {}", url);
-
-                        // enhanced logging for deregistration
-                        let res = http.delete(&url).send().await;
-                        match res {
-                            Ok(resp) => {
-                                let status = resp.status();
-                                println!(
-                                    "deregistration successful for {}/{}: http {}",
-                                    namespace, name, status
-                                );
-
-                                // log response body if available
-                                if let Ok(body) = resp.text().await {
-                                    if !body.is_empty() {
-                                        println!("response body: {}", body);
+                        log::with_request_id(async {
+                            // get service name and namespace
+                            let name = svc.name_any();
+                            let namespace = svc.namespace().unwrap_or("default".to_string());
+                            info!("service event: deleted - {}/{}", namespace, name);
+
+                            // send DELETE request to lb - using same hostname as registration
+                            let url = format!(
+                                "http://load-balancer-service.default.svc.cluster.local:8080/api/unregister/{}",
+                                name
+                            );
+                            debug!("sending deregistration request to: {}", url);
+
+                            // enhanced logging for deregistration
+                            let res = tag_request(http.delete(&url)).send().await;
+                            match res {
+                                Ok(resp) => {
+                                    let status = resp.status();
+                                    info!(
+                                        "deregistration successful for {}/{}: http {}",
+                                        namespace, name, status
+                                    );
+                                    report_auth_failure(status);
+                                    if status.is_success() {
+                                        forget_headers(&name);
+                                    }
+
+                                    // log response body if available
+                                    if let Ok(body) = resp.text().await {
+                                        if !body.is_empty() {
+                                            trace!("response body: {}", body);
+                                        }
                                     }
-                                }
                                 
-                                // sync services after deregistration
-                                if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "post-deregistration").await {
-                                    eprintln!("post-deregistration sync failed: {}", err);
+                                    // sync services after deregistration
+                                    if let Err(err) = sync_services_with_load_balancer(&services, &lp, &http, "post-deregistration").await {
+                                        error!("post-deregistration sync failed: {}", err);
+                                    }
+                                }
+                                Err(err) => {
+                                    error!(
+                                        "deregistration failed for {}/{}: {}",
+                                        namespace, name, err
+                                    );
+                                    warn!("check if lb is running at: {}", url);
                                 }
                             }
-                            Err(err) => {
-                                eprintln!(
-                                    "deregistration failed for {}/{}: {}",
-                                    namespace, name, err
-                                );
-                                eprintln!("check if lb is running at: {}", url);
-                            }
-                        }
+                        })
+                        .await;
                     }
 
                     Some(Ok(event)) => {
-                        println!(
+                        debug!(
                             "received unhandled event type: {:?}",
                             std::mem::discriminant(&event)
                         );
                     }
 
                     Some(Err(err)) => {
-                        eprintln!("watcher error occurred: {}", err);
-                        eprintln!("continuing to watch for service events...");
+                        error!("watcher error occurred: {}", err);
+                        warn!("continuing to watch for service events...");
                     }
 
                     None => {
-                        println!("watcher stream ended");
+                        info!("watcher stream ended");
                         break;
                     }
                 }
@@ -552,6 +780,6 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    println!("watcher stopped");
+    info!("watcher stopped");
     Ok(())
 }
\ No newline at end of file